@@ -0,0 +1,109 @@
+//! Compare throughput and CPU time of copying and splicing data between
+//! two TCP streams, the way a proxy forwards a bulk transfer.
+//!
+//! Run with `cargo bench --bench forwarding`. CPU time is measured for the
+//! whole process, so it includes the sending and receiving peers, which
+//! cost the same in both modes.
+
+use fproxy::splice_bidirectional;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::io::copy_bidirectional;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+
+/// Bytes sent from the client to the target per run.
+const TRANSFER_SIZE: usize = 2 << 30;
+
+/// Size of the writes made by the client.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Copy,
+    Splice,
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    for mode in [Mode::Copy, Mode::Splice] {
+        let cpu_before = cpu_time();
+        let elapsed = runtime.block_on(transfer(mode));
+        let cpu = cpu_time() - cpu_before;
+
+        let gib = TRANSFER_SIZE as f64 / (1u64 << 30) as f64;
+        println!(
+            "{:<8} {:>8.2} GiB/s {:>8.2} cpu-s/GiB",
+            format!("{mode:?}"),
+            gib / elapsed.as_secs_f64(),
+            cpu.as_secs_f64() / gib,
+        );
+    }
+}
+
+/// Forward `TRANSFER_SIZE` bytes from a client to a target through the
+/// given mode, and return how long it took.
+async fn transfer(mode: Mode) -> Duration {
+    let (mut client, mut proxy_in) = pair().await;
+    let (mut proxy_out, mut target) = pair().await;
+
+    let started = Instant::now();
+    let forward = tokio::spawn(async move {
+        match mode {
+            Mode::Copy => copy_bidirectional(&mut proxy_in, &mut proxy_out).await,
+            Mode::Splice => splice_bidirectional(&mut proxy_in, &mut proxy_out).await,
+        }
+    });
+
+    let send = tokio::spawn(async move {
+        let chunk = vec![0xab; CHUNK_SIZE];
+        for _ in 0..TRANSFER_SIZE / CHUNK_SIZE {
+            client.write_all(&chunk).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+        client
+    });
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    loop {
+        match target.read(&mut buf).await.unwrap() {
+            0 => break,
+            len => received += len,
+        }
+    }
+
+    assert_eq!(received, TRANSFER_SIZE);
+    drop(target);
+    drop(send.await.unwrap());
+    let _ = forward.await.unwrap();
+    started.elapsed()
+}
+
+/// Connected pair of TCP streams over loopback.
+async fn pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap());
+    let (client, accepted) = tokio::join!(client, listener.accept());
+    (client.unwrap(), accepted.unwrap().0)
+}
+
+/// User and system CPU time used by the process so far.
+fn cpu_time() -> Duration {
+    // SAFETY: getrusage only writes into the zeroed struct it is given.
+    let usage = unsafe {
+        let mut usage = std::mem::zeroed::<libc::rusage>();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage
+    };
+
+    let as_duration =
+        |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    as_duration(usage.ru_utime) + as_duration(usage.ru_stime)
+}
@@ -6,6 +6,7 @@ pub use self::error::*;
 pub use self::schema::*;
 pub use self::subscribers::*;
 use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::Mutex;
 
 /// Watch for configuration changes.
 pub trait ConfigSubscriber<C> {
@@ -25,15 +26,34 @@ pub struct Subscriber<C, T> {
     #[allow(dead_code)]
     context: C,
 
-    /// Receiver for configuration changes.
-    rx: UnboundedReceiver<T>,
+    /// Receiver for configuration changes, shared so a newer configuration
+    /// can be waited for while applying the previous one.
+    rx: Mutex<UnboundedReceiver<T>>,
+
+    /// Re-read the configuration from the source, sending it to `rx`. Unset
+    /// if the source can't be re-read on demand.
+    reload: Option<Box<dyn Fn() + Send + Sync>>,
 }
 
 impl<C, T> Subscriber<C, T> {
     /// Listen for the next configuration changes. `None` will
     /// only be returned when the underlying channel has been closed
     /// and all received messages have been processed.
-    pub async fn recv(&mut self) -> Option<T> {
-        self.rx.recv().await
+    pub async fn recv(&self) -> Option<T> {
+        self.rx.lock().await.recv().await
+    }
+
+    /// Re-read the configuration from the source, even if it didn't change.
+    /// The configuration is received through [`Subscriber::recv`].
+    ///
+    /// Returns `false` if the source doesn't support reloading.
+    pub fn reload(&self) -> bool {
+        match &self.reload {
+            Some(reload) => {
+                reload();
+                true
+            }
+            None => false,
+        }
     }
 }
@@ -1,6 +1,11 @@
 mod parser;
 
+use ipnet::IpNet;
 use serde::Deserialize;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// App name slug.
 pub type App = String;
@@ -9,16 +14,36 @@ pub type App = String;
 pub type Port = u16;
 
 /// Address for a target.
-#[derive(Debug)]
-pub struct TargetAddr {
-    /// Target address.
-    pub addr: String,
-    /// Target port
-    pub port: Port,
+#[derive(Debug, Clone)]
+pub enum TargetAddr {
+    /// Host name or IP address, and port.
+    Inet {
+        /// Target address.
+        addr: String,
+        /// Target port
+        port: Port,
+    },
+    /// Unix domain socket path, written as `unix:{path}`.
+    Unix(PathBuf),
+}
+
+/// Address an app listens on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    /// Socket address, written either as a bare port (bound on all IPv4
+    /// interfaces), or as `{ip}:{port}` (e.g. `[::]:5001`, `10.1.2.3:5001`).
+    Inet(SocketAddr),
+    /// Unix domain socket path, written as `unix:{path}`.
+    Unix(PathBuf),
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Apps {
+    /// Version of the configuration, e.g. a commit hash or a release tag,
+    /// recorded on every proxy it rolls out.
+    #[serde(rename = "Version", default)]
+    pub version: Option<String>,
+
     #[serde(rename = "Apps")]
     pub apps: Vec<AppConfig>,
 }
@@ -29,8 +54,409 @@ pub struct AppConfig {
     pub name: App,
 
     #[serde(rename = "Ports")]
-    pub ports: Vec<Port>,
+    pub ports: Vec<ListenAddr>,
 
     #[serde(rename = "Targets")]
     pub targets: Vec<TargetAddr>,
+
+    /// Whether IPv6 listen addresses only accept IPv6 clients (`IPV6_V6ONLY`).
+    /// When unset, the system default applies, which on Linux also accepts
+    /// IPv4 clients on `[::]` (dual-stack).
+    #[serde(rename = "Ipv6Only", default)]
+    pub ipv6_only: Option<bool>,
+
+    /// Socket options for the app's listeners, and both legs of the
+    /// connections proxied for it.
+    #[serde(rename = "Socket", default)]
+    pub socket: SocketOptions,
+
+    /// Forward data between plain TCP clients and targets with `splice(2)`,
+    /// without copying it through userspace. Only supported on Linux, and
+    /// sessions with TLS or Unix sockets on either side are still copied.
+    #[serde(rename = "Splice", default)]
+    pub splice: bool,
+
+    /// Close connections once no data has been seen in either direction for
+    /// this long (e.g. `5m`). For UDP apps, this is the session idle timeout.
+    /// Must be at least `1ms`.
+    #[serde(rename = "IdleTimeout", default, with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+
+    /// Close connections once they have been open for this long, whether
+    /// or not they are active (e.g. `24h`). Must be at least `1ms`.
+    #[serde(rename = "MaxConnectionDuration", default, with = "humantime_serde")]
+    pub max_connection_duration: Option<Duration>,
+
+    /// Close connections this long after one side finished sending (FIN),
+    /// if the other side is still sending. Until then, data keeps flowing in
+    /// the other direction. Must be at least `1ms`.
+    #[serde(rename = "HalfCloseTimeout", default, with = "humantime_serde")]
+    pub half_close_timeout: Option<Duration>,
+
+    /// Reset both sides of a connection (`SO_LINGER` 0) when the proxy
+    /// closes it because of a timeout, instead of closing it gracefully.
+    #[serde(rename = "AbortiveClose", default)]
+    pub abortive_close: bool,
+
+    /// Maximum connections open at once across all of the app's listeners.
+    #[serde(rename = "MaxConnections", default)]
+    pub max_connections: Option<usize>,
+
+    /// Maximum connections open at once on each of the app's listeners.
+    /// Apps sharing a port by server name must agree on it.
+    #[serde(rename = "MaxConnectionsPerListener", default)]
+    pub max_connections_per_listener: Option<usize>,
+
+    /// What happens to new connections once a connection limit is reached.
+    /// Apps sharing a port by server name must agree on it.
+    #[serde(rename = "ConnectionLimitPolicy", default)]
+    pub connection_limit_policy: LimitPolicy,
+
+    /// Maximum UDP sessions open at once on each of the app's listeners.
+    #[serde(
+        rename = "MaxUdpSessions",
+        default = "AppConfig::default_max_udp_sessions"
+    )]
+    pub max_udp_sessions: usize,
+
+    /// What happens to datagrams of new clients once `MaxUdpSessions` is
+    /// reached.
+    #[serde(rename = "UdpSessionLimitPolicy", default)]
+    pub udp_session_limit_policy: SessionLimitPolicy,
+
+    /// Networks clients may connect from, e.g. `203.0.113.0/24`. When set,
+    /// clients from any other network are closed before a target is
+    /// connected.
+    #[serde(rename = "Allow", default)]
+    pub allow: Vec<IpNet>,
+
+    /// Networks clients may not connect from, even if allowed by `Allow`.
+    #[serde(rename = "Deny", default)]
+    pub deny: Vec<IpNet>,
+
+    /// Rate of new connections accepted, or UDP sessions opened, from each
+    /// client address. Clients above the rate are closed, or their datagrams
+    /// dropped, before a target is resolved or connected.
+    #[serde(rename = "ClientRateLimit", default)]
+    pub client_rate_limit: Option<ClientRateLimit>,
+
+    /// Throughput caps of the app's TCP sessions.
+    #[serde(rename = "Bandwidth", default)]
+    pub bandwidth: BandwidthLimits,
+
+    /// Overrides of the daemon's retry policy when the app's ports can't be
+    /// bound, e.g. because they are still used by another process.
+    #[serde(rename = "BindRetry", default)]
+    pub bind_retry: BindRetry,
+
+    /// Transport protocol the app's ports and targets speak.
+    #[serde(rename = "Protocol", default)]
+    pub protocol: Protocol,
+
+    /// TLS server names (SNI) the app is reachable on. When set, the app's
+    /// ports are shared with other apps declaring server names, and
+    /// connections are routed by the name in the TLS ClientHello.
+    ///
+    /// Wildcards are supported for a single leading label (`*.example.com`).
+    #[serde(rename = "ServerNames", default)]
+    pub server_names: Vec<String>,
+
+    /// Terminate TLS on the app's listeners, and forward plaintext to targets.
+    #[serde(rename = "Tls", default)]
+    pub tls: Option<TlsConfig>,
+
+    /// Connect to targets over TLS.
+    #[serde(rename = "UpstreamTls", default)]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+}
+
+impl AppConfig {
+    fn default_max_udp_sessions() -> usize {
+        65536
+    }
+}
+
+/// Transport protocol of an app.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Protocol {
+    #[default]
+    #[serde(rename = "TCP", alias = "tcp")]
+    Tcp,
+    #[serde(rename = "UDP", alias = "udp")]
+    Udp,
+}
+
+/// Socket tuning for an app.
+///
+/// Listener options (`Acceptors`, `Backlog`, `FastOpen`, ...) of ports shared by server name
+/// come from the app that first bound the port. Durations are written in
+/// a human readable form, e.g. `30s` or `1m 30s`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// Number of sockets bound to each port, each with its own accept loop.
+    /// The kernel spreads connections across them (`SO_REUSEPORT`).
+    #[serde(rename = "Acceptors")]
+    pub acceptors: usize,
+
+    /// Serve each acceptor, and the connections it accepts, on a dedicated
+    /// single threaded runtime.
+    #[serde(rename = "PerCoreRuntime")]
+    pub per_core_runtime: bool,
+
+    /// Maximum number of pending connections queued on the listener.
+    #[serde(rename = "Backlog")]
+    pub backlog: i32,
+
+    /// Keep a spare file descriptor per acceptor. Once the process runs out
+    /// of file descriptors, it is closed to accept pending connections and
+    /// close them right away, instead of leaving them in the backlog.
+    #[serde(rename = "ReserveFd")]
+    pub reserve_fd: bool,
+
+    /// Disable Nagle's algorithm (`TCP_NODELAY`) on client and target
+    /// connections.
+    #[serde(rename = "NoDelay")]
+    pub no_delay: bool,
+
+    /// Size of the kernel receive buffer (`SO_RCVBUF`) in bytes.
+    #[serde(rename = "RecvBufferSize")]
+    pub recv_buffer_size: Option<usize>,
+
+    /// Size of the kernel send buffer (`SO_SNDBUF`) in bytes.
+    #[serde(rename = "SendBufferSize")]
+    pub send_buffer_size: Option<usize>,
+
+    /// Enable TCP Fast Open, with the maximum number of pending fast open
+    /// requests on the listener. Target connections are also opened with
+    /// `TCP_FASTOPEN_CONNECT`. Only supported on Linux.
+    #[serde(rename = "FastOpen")]
+    pub fast_open: Option<u32>,
+
+    /// How long transmitted data may remain unacknowledged before the
+    /// connection is dropped (`TCP_USER_TIMEOUT`).
+    #[serde(rename = "UserTimeout", with = "humantime_serde")]
+    pub user_timeout: Option<Duration>,
+
+    /// TCP keep alive probes on client and target connections.
+    #[serde(rename = "KeepAlive")]
+    pub keep_alive: KeepAliveOptions,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            acceptors: 1,
+            per_core_runtime: false,
+            backlog: 128,
+            reserve_fd: false,
+            no_delay: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            fast_open: None,
+            user_timeout: None,
+            keep_alive: KeepAliveOptions::default(),
+        }
+    }
+}
+
+/// TCP keep alive settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KeepAliveOptions {
+    /// Idle time before the first probe is sent.
+    #[serde(rename = "Time", with = "humantime_serde")]
+    pub time: Duration,
+
+    /// Time between probes.
+    #[serde(rename = "Interval", with = "humantime_serde")]
+    pub interval: Duration,
+
+    /// Unacknowledged probes before the connection is dropped.
+    #[serde(rename = "Retries")]
+    pub retries: u32,
+}
+
+impl Default for KeepAliveOptions {
+    fn default() -> Self {
+        Self {
+            time: Duration::from_secs(10),
+            interval: Duration::from_secs(75),
+            retries: 9,
+        }
+    }
+}
+
+/// How new connections are handled once a connection limit is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum LimitPolicy {
+    /// Accept and immediately close new connections.
+    #[default]
+    Reject,
+
+    /// Stop accepting until a connection closes, leaving new connections in
+    /// the listener's backlog. Limits of apps on ports shared by server name
+    /// always reject, since the app is only known after accepting.
+    Pause,
+}
+
+/// How datagrams of new clients are handled once a UDP session limit is
+/// reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum SessionLimitPolicy {
+    /// Drop datagrams of new clients until a session expires.
+    #[default]
+    Reject,
+
+    /// Close the session whose client sent a datagram least recently to make
+    /// room for the new client.
+    Evict,
+}
+
+/// Token bucket limiting new connections per client address.
+///
+/// Clients are grouped by network prefix, so all addresses of a prefix share
+/// a bucket. By default, IPv4 clients are limited per address, and IPv6
+/// clients per `/64`, the smallest network usually assigned to a host.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClientRateLimit {
+    /// Connections per second a client may sustain.
+    #[serde(rename = "Rate")]
+    pub rate: f64,
+
+    /// Connections a client may open at once before being limited to the
+    /// rate.
+    #[serde(rename = "Burst")]
+    pub burst: u32,
+
+    /// Prefix length IPv4 clients are grouped by.
+    #[serde(
+        rename = "Ipv4Prefix",
+        default = "ClientRateLimit::default_ipv4_prefix"
+    )]
+    pub ipv4_prefix: u8,
+
+    /// Prefix length IPv6 clients are grouped by.
+    #[serde(
+        rename = "Ipv6Prefix",
+        default = "ClientRateLimit::default_ipv6_prefix"
+    )]
+    pub ipv6_prefix: u8,
+}
+
+impl ClientRateLimit {
+    fn default_ipv4_prefix() -> u8 {
+        32
+    }
+
+    fn default_ipv6_prefix() -> u8 {
+        64
+    }
+}
+
+/// Throughput caps in bytes per second, for each connection and for all
+/// connections of an app combined.
+///
+/// Upload is data sent by clients to targets, and download the reverse.
+/// Sessions of apps with any cap are copied through userspace, even with
+/// `Splice` enabled.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BandwidthLimits {
+    #[serde(rename = "UploadPerConnection")]
+    pub upload_per_connection: Option<u64>,
+
+    #[serde(rename = "DownloadPerConnection")]
+    pub download_per_connection: Option<u64>,
+
+    #[serde(rename = "UploadPerApp")]
+    pub upload_per_app: Option<u64>,
+
+    #[serde(rename = "DownloadPerApp")]
+    pub download_per_app: Option<u64>,
+}
+
+/// Retry policy for binding an app's ports. Unset fields fall back to the
+/// daemon's policy.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BindRetry {
+    /// Attempts to bind a port before giving up, including the first one.
+    #[serde(rename = "Attempts")]
+    pub attempts: Option<u8>,
+
+    /// Back-off before the first retry, doubled on every further retry.
+    /// Each delay is jittered down to as little as half the back-off.
+    #[serde(rename = "InitialDelay", with = "humantime_serde")]
+    pub initial_delay: Option<Duration>,
+
+    /// Cap on the back-off between retries. Jittered like the back-off, so
+    /// a delay is never longer, but may be as short as half of it.
+    #[serde(rename = "MaxDelay", with = "humantime_serde")]
+    pub max_delay: Option<Duration>,
+}
+
+/// TLS termination settings for an app.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// Path to the PEM encoded certificate chain.
+    #[serde(rename = "Certificate")]
+    pub cert_path: PathBuf,
+
+    /// Path to the PEM encoded private key.
+    #[serde(rename = "Key")]
+    pub key_path: PathBuf,
+
+    /// Protocols to offer during ALPN, in order of preference.
+    #[serde(rename = "Alpn", default)]
+    pub alpn: Vec<String>,
+
+    /// Minimum TLS version accepted from clients.
+    #[serde(rename = "MinVersion", default)]
+    pub min_version: TlsVersion,
+}
+
+/// TLS settings for connections from the proxy to an app's targets.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamTlsConfig {
+    /// Server name to send and verify, instead of the target's address.
+    #[serde(rename = "ServerName", default)]
+    pub server_name: Option<String>,
+
+    /// Path to the PEM encoded CA certificates to trust. Defaults to the
+    /// Mozilla root certificates.
+    #[serde(rename = "CaBundle", default)]
+    pub ca_bundle: Option<PathBuf>,
+
+    /// Path to the PEM encoded client certificate chain for mutual TLS.
+    #[serde(rename = "Certificate", default)]
+    pub cert_path: Option<PathBuf>,
+
+    /// Path to the PEM encoded client private key for mutual TLS.
+    #[serde(rename = "Key", default)]
+    pub key_path: Option<PathBuf>,
+
+    /// How the target's certificate is verified.
+    #[serde(rename = "Verify", default)]
+    pub verify: TlsVerify,
+}
+
+/// Verification of a target's TLS certificate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TlsVerify {
+    /// Verify the certificate chain and the server name.
+    #[default]
+    Full,
+    /// Accept any certificate. Only meant for testing.
+    None,
+}
+
+/// TLS protocol version.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
 }
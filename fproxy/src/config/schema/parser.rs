@@ -1,23 +1,44 @@
+use crate::config::schema::ListenAddr;
 use crate::config::schema::TargetAddr;
 use serde::de::Error;
 use serde::Deserialize;
 use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use thiserror::Error;
 
+/// Prefix for addresses referring to a Unix domain socket path.
+const UNIX_PREFIX: &str = "unix:";
+
 #[derive(Error, Debug)]
 enum ParseTargetError {
-    #[error("invalid address format (expected {{address}}:{{port}})")]
+    #[error("invalid address format (expected {{address}}:{{port}} or unix:{{path}})")]
     InvalidFormat,
     #[error("invalid port number")]
     InvalidPort,
 }
 
+#[derive(Error, Debug)]
+enum ParseListenAddrError {
+    #[error("invalid listen address (expected {{port}}, {{ip}}:{{port}} or unix:{{path}})")]
+    InvalidFormat,
+}
+
 impl<'de> Deserialize<'de> for TargetAddr {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
         let value: &str = Deserialize::deserialize(deserializer)?;
+        if let Some(path) = value.strip_prefix(UNIX_PREFIX) {
+            return Ok(TargetAddr::Unix(PathBuf::from(path)));
+        }
+
         let (addr, port) = value
             .rsplit_once(':')
             .ok_or_else(|| Error::custom(ParseTargetError::InvalidFormat))?;
@@ -26,9 +47,105 @@ impl<'de> Deserialize<'de> for TargetAddr {
             .parse::<u16>()
             .map_err(|_| Error::custom(ParseTargetError::InvalidPort))?;
 
-        Ok(TargetAddr {
+        Ok(TargetAddr::Inet {
             addr: addr.to_owned(),
             port,
         })
     }
 }
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Port(u16),
+            Addr(String),
+        }
+
+        match Deserialize::deserialize(deserializer)? {
+            Raw::Port(port) => Ok(ListenAddr::Inet(SocketAddr::from((
+                Ipv4Addr::UNSPECIFIED,
+                port,
+            )))),
+            Raw::Addr(value) => match value.strip_prefix(UNIX_PREFIX) {
+                Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+                None => value
+                    .parse::<SocketAddr>()
+                    .map(ListenAddr::Inet)
+                    .map_err(|_| Error::custom(ParseListenAddrError::InvalidFormat)),
+            },
+        }
+    }
+}
+
+impl Serialize for ListenAddr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Inet(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+impl Display for TargetAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetAddr::Inet { addr, port } => write!(f, "{addr}:{port}"),
+            TargetAddr::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::ListenAddr;
+    use crate::config::TargetAddr;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_addresses_works() {
+        let ports: Vec<ListenAddr> =
+            serde_json::from_str(r#"[5001, "[::]:5001", "10.1.2.3:5001", "unix:/run/app.sock"]"#)
+                .unwrap();
+        assert_eq!(
+            ports,
+            vec![
+                ListenAddr::Inet("0.0.0.0:5001".parse().unwrap()),
+                ListenAddr::Inet("[::]:5001".parse().unwrap()),
+                ListenAddr::Inet("10.1.2.3:5001".parse().unwrap()),
+                ListenAddr::Unix(PathBuf::from("/run/app.sock"))
+            ]
+        );
+
+        let targets: Vec<TargetAddr> =
+            serde_json::from_str(r#"["tcp-echo.fly.dev:5001", "unix:/run/sidecar.sock"]"#).unwrap();
+        assert!(matches!(
+            &targets[0],
+            TargetAddr::Inet { addr, port: 5001 } if addr == "tcp-echo.fly.dev"
+        ));
+        assert!(matches!(
+            &targets[1],
+            TargetAddr::Unix(path) if path == &PathBuf::from("/run/sidecar.sock")
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid_addresses() {
+        assert!(serde_json::from_str::<ListenAddr>(r#""/run/app.sock""#).is_err());
+        assert!(serde_json::from_str::<ListenAddr>(r#""localhost:5001""#).is_err());
+        assert!(serde_json::from_str::<TargetAddr>(r#""tcp-echo.fly.dev""#).is_err());
+    }
+}
@@ -14,14 +14,14 @@ use notify::Config;
 use notify::Error as NotifyError;
 use notify::Event;
 use notify::EventKind;
-use notify::FsEventWatcher;
 use notify::RecommendedWatcher;
 use notify::RecursiveMode;
 use notify::Watcher;
 use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::Mutex;
 
 /// File watcher context
-pub struct FileContext(FsEventWatcher);
+pub struct FileContext(#[allow(dead_code)] RecommendedWatcher);
 
 /// File watcher.
 pub struct ConfigFileSubscriber<P: AsRef<Path>>(P);
@@ -42,6 +42,7 @@ impl<P: AsRef<Path>> ConfigSubscriber<FileContext> for ConfigFileSubscriber<P> {
         tx.send(fetch_config(&self.0)?)
             .map_err(|e| WatcherError::Other(Box::new(e)))?;
 
+        let watcher_tx = tx.clone();
         let event_handler = move |result: Result<Event, NotifyError>| {
             futures::executor::block_on(async {
                 match result {
@@ -62,7 +63,7 @@ impl<P: AsRef<Path>> ConfigSubscriber<FileContext> for ConfigFileSubscriber<P> {
                             Ok(content) => match serde_json::from_str(&content) {
                                 Err(err) => error!("failed to parse config file: {}", err),
                                 Ok::<Apps, _>(config) => {
-                                    if let Err(err) = tx.send(config) {
+                                    if let Err(err) = watcher_tx.send(config) {
                                         error!("failed to send config to receiver: {}", err);
                                     }
                                 }
@@ -73,12 +74,23 @@ impl<P: AsRef<Path>> ConfigSubscriber<FileContext> for ConfigFileSubscriber<P> {
             })
         };
 
+        let path = self.0.as_ref().to_path_buf();
+        let reload = move || match fetch_config(&path) {
+            Err(err) => error!("failed to reload config file: {}", err),
+            Ok(config) => {
+                if let Err(err) = tx.send(config) {
+                    error!("failed to send config to receiver: {}", err);
+                }
+            }
+        };
+
         let mut watcher = RecommendedWatcher::new(event_handler, Config::default())?;
         watcher.watch(self.0.as_ref(), RecursiveMode::Recursive)?;
 
         Ok(Subscriber {
             context: FileContext(watcher),
-            rx,
+            rx: Mutex::new(rx),
+            reload: Some(Box::new(reload)),
         })
     }
 }
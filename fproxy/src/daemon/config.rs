@@ -1,5 +1,5 @@
-use std::sync::Arc;
-
+use std::path::PathBuf;
+use std::time::Duration;
 use trust_dns_resolver::TokioAsyncResolver;
 use typed_builder::TypedBuilder;
 
@@ -12,11 +12,35 @@ use super::utils::BindSocketRetryOption;
 #[derive(TypedBuilder)]
 pub struct DaemonConfig<C> {
     /// DNS resolver.
-    pub dns_resolver: Arc<&'static TokioAsyncResolver>,
+    pub dns_resolver: &'static TokioAsyncResolver,
 
     /// Handle to listen for  configuration change.
     pub config_subscriber: Subscriber<C, Apps>,
 
     /// Retry configuration when attempting to bind to host's socket address.
     pub bind_socket_retry_option: BindSocketRetryOption,
+
+    /// Maximum connections open at once across every app. New connections
+    /// beyond it are handled according to each app's limit policy.
+    #[builder(default)]
+    pub max_connections: Option<usize>,
+
+    /// Max time to wait for every proxy to drain once the daemon shuts down.
+    ///
+    /// Default value: 30 seconds
+    #[builder(default = Duration::from_secs(30))]
+    pub shutdown_timeout: Duration,
+
+    /// Apply each configuration as a whole: if any app fails to apply, no
+    /// app is changed. By default, apps failing to apply keep their previous
+    /// configuration while the other apps are updated.
+    #[builder(default)]
+    pub atomic_rollout: bool,
+
+    /// Unix socket path upgrades go through. On startup, the daemon takes
+    /// over the listening sockets of a daemon already serving the path, and
+    /// tells it to shut down once they are served. It then serves the path
+    /// itself, handing its sockets over to the next upgrade.
+    #[builder(default)]
+    pub upgrade_socket: Option<PathBuf>,
 }
@@ -1,3 +1,5 @@
+use crate::config::ListenAddr;
+use crate::tls::TlsError;
 use crate::WatcherError;
 use std::io::Error as IoError;
 use std::net::AddrParseError;
@@ -7,6 +9,19 @@ pub enum DaemonError {
     ConfigWatcher(WatcherError),
     ParseAddr(AddrParseError),
     IoError(IoError),
+    Tls(TlsError),
+    /// App configuration combines options that can't be used together.
+    InvalidConfig(String),
+    /// Address is already used by another app in a different listener mode.
+    AddrConflict(ListenAddr),
+    /// Server name is already routed to another app on the same port.
+    ServerNameConflict(String),
+    /// Port is shared by server name with another app setting different
+    /// listener limits.
+    LimitConflict(ListenAddr),
+    /// Listeners taken over from the previous daemon couldn't be served, so
+    /// it was left serving them.
+    UpgradeAborted(Vec<ListenAddr>),
 }
 
 impl From<WatcherError> for DaemonError {
@@ -26,3 +41,9 @@ impl From<IoError> for DaemonError {
         Self::IoError(error)
     }
 }
+
+impl From<TlsError> for DaemonError {
+    fn from(error: TlsError) -> Self {
+        Self::Tls(error)
+    }
+}
@@ -0,0 +1,137 @@
+use crate::config::App;
+use crate::config::ListenAddr;
+use crate::config::Protocol;
+use crate::proxy::AcceptErrorCounters;
+use crate::proxy::AccessCounters;
+use crate::proxy::Generation;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+
+/// Counters of a running daemon, read through a
+/// [`DaemonHandle`](crate::DaemonHandle).
+///
+/// Counters are kept for as long as the daemon runs, across configuration
+/// rollouts.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Accept loops restarted after failing, by listening address.
+    restarts: DashMap<(ListenAddr, Protocol), AtomicU64>,
+    /// Accept errors, by listening address.
+    accept_errors: DashMap<(ListenAddr, Protocol), Arc<AcceptErrorCounters>>,
+    /// Access list decisions, by app.
+    access: DashMap<App, Arc<AccessCounters>>,
+    /// Latest configuration rollout started.
+    generation: Mutex<Generation>,
+    /// Configuration rollout each listener was last updated by.
+    listener_generations: DashMap<(ListenAddr, Protocol), Generation>,
+}
+
+impl Metrics {
+    /// Accept loops restarted after failing so far, by listening address.
+    pub fn restarts(&self) -> HashMap<(ListenAddr, Protocol), u64> {
+        self.restarts
+            .iter()
+            .map(|count| (count.key().to_owned(), count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Accept loops restarted after failing so far, across every listener.
+    pub fn total_restarts(&self) -> u64 {
+        self.restarts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Accept errors so far, by listening address.
+    pub fn accept_errors(&self) -> HashMap<(ListenAddr, Protocol), Arc<AcceptErrorCounters>> {
+        self.accept_errors
+            .iter()
+            .map(|counters| (counters.key().to_owned(), counters.value().clone()))
+            .collect()
+    }
+
+    /// Access list decisions so far, by app. Apps without an access list
+    /// have none.
+    pub fn access_decisions(&self) -> HashMap<App, Arc<AccessCounters>> {
+        self.access
+            .iter()
+            .map(|counters| (counters.key().to_owned(), counters.value().clone()))
+            .collect()
+    }
+
+    /// Counters of the access list decisions of `app`, shared by every
+    /// configuration of the app.
+    pub(crate) fn access_counters(&self, app: &str) -> Arc<AccessCounters> {
+        self.access.entry(app.to_owned()).or_default().clone()
+    }
+
+    /// Counters of the accept errors on `port`, shared by every proxy
+    /// serving it.
+    pub(crate) fn accept_error_counters(
+        &self,
+        port: &ListenAddr,
+        protocol: Protocol,
+    ) -> Arc<AcceptErrorCounters> {
+        self.accept_errors
+            .entry((port.to_owned(), protocol))
+            .or_default()
+            .clone()
+    }
+
+    /// Latest configuration rollout started.
+    pub fn generation(&self) -> Generation {
+        self.generation
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Configuration rollout each listener was last updated by, i.e. its
+    /// proxy was started by, or for listeners shared by server name, its
+    /// routes were last changed by.
+    pub fn listener_generations(&self) -> HashMap<(ListenAddr, Protocol), Generation> {
+        self.listener_generations
+            .iter()
+            .map(|generation| (generation.key().to_owned(), generation.value().clone()))
+            .collect()
+    }
+
+    /// Record the start of a configuration rollout.
+    pub(crate) fn start_rollout(&self, generation: &Generation) {
+        *self
+            .generation
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = generation.clone();
+    }
+
+    /// Record the configuration rollout `port` was updated by.
+    pub(crate) fn update_listener(
+        &self,
+        port: &ListenAddr,
+        protocol: Protocol,
+        generation: &Generation,
+    ) {
+        self.listener_generations
+            .insert((port.to_owned(), protocol), generation.clone());
+    }
+
+    /// Stop tracking the generation of a listener that was shut down.
+    pub(crate) fn remove_listener(&self, port: &ListenAddr, protocol: Protocol) {
+        self.listener_generations
+            .remove(&(port.to_owned(), protocol));
+    }
+
+    /// Count a restart of an accept loop serving `port`.
+    pub(crate) fn count_restart(&self, port: &ListenAddr, protocol: Protocol) {
+        self.restarts
+            .entry((port.to_owned(), protocol))
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
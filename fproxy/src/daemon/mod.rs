@@ -1,100 +1,2029 @@
 mod config;
 mod error;
+mod metrics;
+mod supervisor;
+mod systemd;
+mod upgrade;
 mod utils;
 
 pub use self::config::DaemonConfig;
 pub use self::error::DaemonError;
+pub use self::metrics::Metrics;
 pub use self::utils::BindSocketRetryOption;
 use crate::config::App;
 use crate::config::AppConfig;
-use crate::config::Port;
-use crate::daemon::utils::bind_with_addr_and_port_reuse;
+use crate::config::Apps;
+use crate::config::BandwidthLimits;
+use crate::config::LimitPolicy;
+use crate::config::ListenAddr;
+use crate::config::Protocol;
+use crate::config::TargetAddr;
+use crate::daemon::supervisor::supervise;
+use crate::daemon::supervisor::SUPERVISE_INTERVAL;
+use crate::daemon::systemd::activated_listeners;
+use crate::daemon::systemd::find_activated;
+use crate::daemon::systemd::notify;
+use crate::daemon::systemd::notify_reloading;
+use crate::daemon::systemd::spawn_watchdog;
+use crate::daemon::systemd::Activated;
+use crate::daemon::upgrade::notify_ready;
+use crate::daemon::upgrade::take_over;
+use crate::daemon::upgrade::HandedOver;
+use crate::daemon::utils::adopt_listener;
+use crate::daemon::utils::bind_listeners;
+use crate::daemon::utils::bind_unix_with_replace;
+use crate::daemon::utils::BoundSocket;
+use crate::proxy::AccessList;
+use crate::proxy::BandwidthLimiter;
+use crate::proxy::ClientRateLimiter;
+use crate::proxy::Generation;
 use crate::proxy::Proxy;
 use crate::proxy::ProxyConfig;
+use crate::proxy::Route;
+use crate::proxy::Router;
+use crate::proxy::ServerNameRouter;
+use crate::proxy::SharedBandwidth;
+use crate::proxy::ShutdownReport;
 use crate::strategy::RoundRobinStrategy;
+use crate::tls::TlsTerminator;
+use crate::tls::UpstreamTls;
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
 use futures::future::join_all;
 use log::as_serde;
+use log::error;
 use log::info;
 use log::warn;
+use sd_notify::NotifyState;
+use std::collections::HashMap;
+use std::future::pending;
+use std::hash::Hash;
+use std::io;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Duration;
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+use tokio::spawn;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
 
 /// Process managing proxy and rolling out changes.
 pub struct Daemon<C> {
     /// Daemon configuration.
     config: DaemonConfig<C>,
-    /// Directory of application proxy context.
-    apps: DashMap<App, DashMap<Port, Proxy>>,
+    /// Directory of application proxy context, shared with the supervisor.
+    apps: Arc<DashMap<App, DashMap<ListenAddr, Proxy>>>,
+    /// Listeners shared between apps and routed by TLS server name.
+    shared_listeners: Arc<DashMap<ListenAddr, SharedListener>>,
+    /// Connections open at once across every app.
+    connection_limit: Option<Arc<Semaphore>>,
+    /// Connections open at once of each app that has a limit.
+    app_limits: DashMap<App, AppLimit>,
+    /// Throughput caps of each app that has one.
+    app_bandwidth: DashMap<App, AppBandwidth>,
+    /// Client rate limiters of each app that has one, so clients stay
+    /// limited across rollouts that don't change the limit.
+    client_rate_limiters: DashMap<App, Arc<ClientRateLimiter>>,
+    /// Sender of commands handed out through [`DaemonHandle`]s.
+    commands_tx: UnboundedSender<Command>,
+    /// Commands sent to the running daemon, shared so they can be received
+    /// while a rollout is in progress.
+    commands_rx: AsyncMutex<UnboundedReceiver<Command>>,
+    /// Listening sockets handed over by the daemon being upgraded, until
+    /// the first configuration is applied.
+    inherited: Mutex<HandedOver>,
+    /// Connection to the daemon being upgraded, told to shut down once the
+    /// first configuration is applied.
+    previous: Option<StdUnixStream>,
+    /// Listening sockets passed by the service manager, served instead of
+    /// binding their address for as long as the daemon runs.
+    activated: Activated,
+    /// Counters shared with [`DaemonHandle`]s.
+    metrics: Arc<Metrics>,
+    /// Configuration rollouts started so far, numbering the generations of
+    /// proxies.
+    generations: AtomicU64,
+}
+
+/// Max time to wait for a new daemon to serve the sockets handed over to it.
+const UPGRADE_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Request sent to a running daemon.
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    /// Re-read the configuration from its source.
+    Reload,
+    /// Stop applying configuration changes and drain every proxy.
+    Shutdown,
+}
+
+/// Handle to control a running daemon, e.g. from a signal handler.
+#[derive(Debug, Clone)]
+pub struct DaemonHandle {
+    tx: UnboundedSender<Command>,
+    metrics: Arc<Metrics>,
+}
+
+impl DaemonHandle {
+    /// Re-read and apply the configuration, even if it didn't change.
+    pub fn reload(&self) {
+        self.send(Command::Reload);
+    }
+
+    /// Shut down every proxy, and return from [`Daemon::start`].
+    pub fn shutdown(&self) {
+        self.send(Command::Shutdown);
+    }
+
+    /// Counters of the daemon.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    fn send(&self, command: Command) {
+        if self.tx.send(command).is_err() {
+            warn!("daemon is no longer running, ignoring {command:?}");
+        }
+    }
+}
+
+/// App configuration whose listeners are bound, ready to replace the app's
+/// proxies. `P` is a [`PreparedProxy`] until the app's proxies are started.
+struct PreparedApp<P = PreparedProxy> {
+    config: AppConfig,
+    route: Arc<Route>,
+    listeners: PreparedListeners<P>,
+}
+
+/// Proxies of an app, not swapped in yet.
+enum PreparedListeners<P = PreparedProxy> {
+    /// Proxy for each port the app owns.
+    Dedicated(Vec<(ListenAddr, P)>),
+
+    /// Proxy for each shared port no listener serves yet.
+    Shared(Vec<(ListenAddr, Arc<ServerNameRouter>, P)>),
+}
+
+/// Proxy configuration along with the sockets it will listen on, which take
+/// no connections until the proxy is started.
+struct PreparedProxy {
+    config: ProxyConfig,
+    sockets: Vec<BoundSocket>,
+}
+
+impl PreparedProxy {
+    /// Start listening on the sockets, and serving them.
+    fn start(self) -> Result<Proxy, DaemonError> {
+        let Self {
+            mut config,
+            sockets,
+        } = self;
+        config.listeners = sockets
+            .into_iter()
+            .map(BoundSocket::activate)
+            .collect::<Result<_, _>>()?;
+        Ok(Proxy::listen(config))
+    }
+}
+
+impl PreparedApp {
+    /// Start the app's proxies, so they only need to be swapped in. Sockets
+    /// of proxies that couldn't start are closed along with the others.
+    fn start(self) -> Result<PreparedApp<Proxy>, DaemonError> {
+        let listeners = match self.listeners {
+            PreparedListeners::Dedicated(proxies) => PreparedListeners::Dedicated(
+                proxies
+                    .into_iter()
+                    .map(|(port, proxy)| Ok((port, proxy.start()?)))
+                    .collect::<Result<_, DaemonError>>()?,
+            ),
+            PreparedListeners::Shared(proxies) => PreparedListeners::Shared(
+                proxies
+                    .into_iter()
+                    .map(|(port, router, proxy)| Ok((port, router, proxy.start()?)))
+                    .collect::<Result<_, DaemonError>>()?,
+            ),
+        };
+
+        Ok(PreparedApp {
+            config: self.config,
+            route: self.route,
+            listeners,
+        })
+    }
+}
+
+/// Proxy listening on a port shared by multiple apps.
+#[derive(Debug)]
+struct SharedListener {
+    /// Server name routes of every app on the port.
+    router: Arc<ServerNameRouter>,
+    /// Proxy accepting connections on the port.
+    proxy: Proxy,
+    /// Limits of the proxy, which every app on the port must agree on.
+    limits: ListenerLimits,
+}
+
+/// Connection limits of a listener, rather than of the apps it serves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ListenerLimits {
+    max_connections: Option<usize>,
+    policy: LimitPolicy,
+}
+
+impl ListenerLimits {
+    fn of(app_config: &AppConfig) -> Self {
+        Self {
+            max_connections: app_config.max_connections_per_listener,
+            policy: app_config.connection_limit_policy,
+        }
+    }
+}
+
+/// Connection limit of an app, kept across rollouts so connections still
+/// open on replaced proxies count against the next configuration.
+#[derive(Debug)]
+struct AppLimit {
+    max: usize,
+    semaphore: Arc<Semaphore>,
+}
+
+impl AppLimit {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            semaphore: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    /// Change the limit. Lowering it takes effect as connections close.
+    fn resize(&mut self, max: usize) {
+        match max.cmp(&self.max) {
+            std::cmp::Ordering::Greater => self.semaphore.add_permits(max - self.max),
+            std::cmp::Ordering::Less => {
+                let semaphore = self.semaphore.clone();
+                let excess = u32::try_from(self.max - max).unwrap_or(u32::MAX);
+                spawn(async move {
+                    if let Ok(permits) = semaphore.acquire_many_owned(excess).await {
+                        permits.forget();
+                    }
+                });
+            }
+            std::cmp::Ordering::Equal => {}
+        }
+        self.max = max;
+    }
+}
+
+/// Throughput caps of an app, kept across rollouts so sessions still open
+/// on replaced proxies count against the next configuration.
+#[derive(Debug, Default)]
+struct AppBandwidth {
+    upload: Option<SharedBandwidth>,
+    download: Option<SharedBandwidth>,
+}
+
+impl AppBandwidth {
+    /// Upload and download caps of a configuration, reusing the current
+    /// ones. Their rates are changed once the configuration is committed.
+    fn caps(
+        &mut self,
+        limits: &BandwidthLimits,
+    ) -> (Option<SharedBandwidth>, Option<SharedBandwidth>) {
+        let keep = |cap: &mut Option<SharedBandwidth>, rate: Option<u64>| {
+            rate.map(|rate| {
+                cap.get_or_insert_with(|| SharedBandwidth::new(rate))
+                    .clone()
+            })
+        };
+        (
+            keep(&mut self.upload, limits.upload_per_app),
+            keep(&mut self.download, limits.download_per_app),
+        )
+    }
+
+    /// Apply the rates of a committed configuration, dropping the caps it
+    /// doesn't set.
+    fn update(&mut self, limits: &BandwidthLimits) {
+        for (cap, rate) in [
+            (&mut self.upload, limits.upload_per_app),
+            (&mut self.download, limits.download_per_app),
+        ] {
+            match (cap.as_ref(), rate) {
+                (Some(cap), Some(rate)) => cap.set_rate(rate),
+                (_, None) => *cap = None,
+                (None, Some(_)) => {}
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.upload.is_none() && self.download.is_none()
+    }
 }
 
 impl<C> Daemon<C> {
     /// Initialize a instance of the proxy daemon.
     pub fn new(config: DaemonConfig<C>) -> Result<Self, DaemonError> {
+        let (commands_tx, commands_rx) = unbounded_channel();
+        let (previous, inherited) = match config.upgrade_socket.as_deref().map(take_over) {
+            Some(Ok(Some((previous, inherited)))) => {
+                info!(listeners = inherited.len(); "took over listeners of previous daemon");
+                (Some(previous), inherited)
+            }
+            Some(Err(error)) => {
+                warn!("failed to take over listeners, binding them instead: {error}");
+                (None, HandedOver::new())
+            }
+            Some(Ok(None)) | None => (None, HandedOver::new()),
+        };
+
+        let activated = activated_listeners().unwrap_or_else(|error| {
+            warn!("failed to adopt sockets from service manager: {error}");
+            Activated::new()
+        });
+
         Ok(Self {
-            apps: DashMap::new(),
+            apps: Arc::default(),
+            shared_listeners: Arc::default(),
+            connection_limit: config
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            app_limits: DashMap::new(),
+            app_bandwidth: DashMap::new(),
+            client_rate_limiters: DashMap::new(),
             config,
+            commands_tx,
+            commands_rx: AsyncMutex::new(commands_rx),
+            inherited: Mutex::new(inherited),
+            previous,
+            activated,
+            metrics: Arc::default(),
+            generations: AtomicU64::new(0),
         })
     }
 
+    /// Handle to control the daemon once started.
+    pub fn handle(&self) -> DaemonHandle {
+        DaemonHandle {
+            tx: self.commands_tx.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
     /// Start the daemaon process.
-    pub async fn start(&mut self) {
-        while let Some(config) = self.config.config_subscriber.recv().await {
-            let app_update_futures = config
-                .apps
-                .into_iter()
-                .map(|config| self.apply_app_config(config));
-
-            join_all(app_update_futures)
-                .await
-                .into_iter()
-                .for_each(|result| {
-                    if let Err(error) = result {
-                        // Improvement: Add support for sending events for app & target which failed
-                        // which can be rendered to the end-user.
-                        warn!("failed to apply configuration {:?}", error)
+    ///
+    /// Configuration changes are applied until the daemon is shut down
+    /// through a [`DaemonHandle`], or the configuration source closes. Every
+    /// proxy is then drained until the daemon's shutdown timeout.
+    ///
+    /// When upgrading, the previous daemon is told to shut down once the
+    /// first configuration is applied. The daemon then hands its listeners
+    /// over to the next upgrade, and shuts down once they are served. If
+    /// an app listening on an inherited socket fails to apply instead, the
+    /// previous daemon is left serving and the daemon shuts down without
+    /// reporting itself ready, returning [`DaemonError::UpgradeAborted`].
+    ///
+    /// Under a service manager, the daemon reports itself ready once the
+    /// first configuration is applied, reloading while applying later ones,
+    /// and stopping once shut down. It also feeds the watchdog, if enabled.
+    ///
+    /// Meanwhile, accept loops that fail are restarted, even while a rollout
+    /// is held up, e.g. retrying to bind a port.
+    pub async fn start(&mut self) -> Result<ShutdownReport, DaemonError> {
+        let mut upgrades = match self.previous {
+            None => self.listen_for_upgrades(),
+            Some(_) => None,
+        };
+        let watchdog = spawn_watchdog();
+        let mut ready = false;
+        let supervisor = self.spawn_supervisor();
+        let mut aborted = None;
+
+        loop {
+            tokio::select! {
+                Some(command) = self.commands_rx.get_mut().recv() => match command {
+                    Command::Shutdown => break,
+                    Command::Reload => self.reload(),
+                },
+                config = self.config.config_subscriber.recv() => {
+                    let Some(config) = config else {
+                        break;
+                    };
+
+                    if ready {
+                        notify_reloading();
                     }
-                });
+
+                    let Some(failed) = self.roll_out(config).await else {
+                        break;
+                    };
+                    if self.previous.is_some() {
+                        match self.finish_take_over(&failed) {
+                            Ok(listener) => upgrades = listener,
+                            Err(error) => {
+                                aborted = Some(error);
+                                break;
+                            }
+                        }
+                    }
+
+                    notify(&[NotifyState::Ready]);
+                    ready = true;
+                }
+                Ok(stream) = accept_upgrade(upgrades.as_ref()) => self.hand_over(stream),
+            }
+        }
+
+        supervisor.abort();
+        let report = self.shutdown().await;
+        if let Some(watchdog) = watchdog {
+            watchdog.abort();
+        }
+
+        match aborted {
+            Some(error) => Err(error),
+            None => Ok(report),
         }
     }
 
+    /// Restart the failed accept loops of every proxy in a task of its own,
+    /// so rollouts don't hold it up.
+    fn spawn_supervisor(&self) -> JoinHandle<()> {
+        let apps = self.apps.clone();
+        let shared_listeners = self.shared_listeners.clone();
+        let metrics = self.metrics.clone();
+        spawn(async move {
+            let mut supervision = interval(SUPERVISE_INTERVAL);
+            loop {
+                supervision.tick().await;
+                for mut proxies in apps.iter_mut() {
+                    let (app, proxies) = proxies.pair_mut();
+                    for mut proxy in proxies.iter_mut() {
+                        let (port, proxy) = proxy.pair_mut();
+                        supervise(Some(app), port, proxy, &metrics);
+                    }
+                }
+
+                for mut listener in shared_listeners.iter_mut() {
+                    let (port, listener) = listener.pair_mut();
+                    supervise(None, port, &mut listener.proxy, &metrics);
+                }
+            }
+        })
+    }
+
+    /// Serve the upgrade socket, if the daemon has one.
+    fn listen_for_upgrades(&self) -> Option<UnixListener> {
+        let path = self.config.upgrade_socket.as_ref()?;
+        match bind_unix_with_replace(path) {
+            Ok(listener) => Some(listener),
+            Err(error) => {
+                warn!("failed to listen for upgrades: {:?}", error);
+                None
+            }
+        }
+    }
+
+    /// Take over the upgrade socket, close inherited listeners the
+    /// configuration no longer uses, and tell the previous daemon to shut
+    /// down.
+    ///
+    /// Fails without telling the previous daemon anything if an inherited
+    /// listener belongs to an app that `failed` to apply: the previous daemon
+    /// keeps serving it once this daemon exits.
+    fn finish_take_over(
+        &mut self,
+        failed: &[(ListenAddr, Protocol)],
+    ) -> Result<Option<UnixListener>, DaemonError> {
+        let mut inherited = self
+            .inherited
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let stranded = inherited
+            .keys()
+            .filter(|key| failed.contains(key))
+            .map(|(port, _)| port.to_owned())
+            .collect::<Vec<_>>();
+        if !stranded.is_empty() {
+            let ports = stranded.iter().map(ToString::to_string).collect::<Vec<_>>();
+            error!(
+                ports = as_serde!(ports);
+                "failed to serve listeners taken over, leaving previous daemon serving"
+            );
+            return Err(DaemonError::UpgradeAborted(stranded));
+        }
+        inherited.clear();
+        drop(inherited);
+
+        let upgrades = self.listen_for_upgrades();
+
+        if let Some(mut previous) = self.previous.take() {
+            match notify_ready(&mut previous) {
+                Ok(()) => info!("told previous daemon to shut down"),
+                Err(error) => warn!("failed to tell previous daemon to shut down: {error}"),
+            }
+        }
+
+        Ok(upgrades)
+    }
+
+    /// Hand every listening socket over to a new daemon, and shut down once
+    /// it serves them.
+    fn hand_over(&self, stream: UnixStream) {
+        let listeners = match self.duplicate_listeners() {
+            Ok(listeners) => listeners,
+            Err(error) => {
+                warn!("failed to duplicate listeners for upgrade: {error}");
+                return;
+            }
+        };
+
+        let commands = self.commands_tx.clone();
+        spawn(async move {
+            let result: io::Result<()> = async {
+                let mut stream = stream.into_std()?;
+                stream.set_nonblocking(false)?;
+                spawn_blocking(move || {
+                    upgrade::hand_over(&mut stream, listeners, UPGRADE_READY_TIMEOUT)
+                })
+                .await?
+            }
+            .await;
+
+            match result {
+                Ok(()) => {
+                    info!("listeners handed over to new daemon");
+                    let _ = commands.send(Command::Shutdown);
+                }
+                Err(error) => warn!("failed to hand listeners over: {error}"),
+            }
+        });
+    }
+
+    /// Duplicate the listening sockets of every proxy.
+    fn duplicate_listeners(&self) -> io::Result<HandedOver> {
+        let duplicate = |proxy: &Proxy| -> io::Result<Vec<OwnedFd>> {
+            proxy
+                .listeners()
+                .iter()
+                .map(|listener| listener.try_clone_fd())
+                .collect()
+        };
+
+        let mut listeners = HandedOver::new();
+        for app in self.apps.iter() {
+            for proxy in app.value().iter() {
+                let key = (proxy.key().to_owned(), proxy.protocol());
+                listeners.insert(key, duplicate(proxy.value())?);
+            }
+        }
+
+        for listener in self.shared_listeners.iter() {
+            let key = (listener.key().to_owned(), listener.proxy.protocol());
+            listeners.insert(key, duplicate(&listener.proxy)?);
+        }
+
+        Ok(listeners)
+    }
+
+    /// Re-read the configuration from its source.
+    fn reload(&self) {
+        info!("reloading configuration");
+        if !self.config.config_subscriber.reload() {
+            warn!("configuration source doesn't support reloading");
+        }
+    }
+
+    /// Apply a configuration, starting over with a newer one if it arrives
+    /// before the rollout completes, e.g. while binding a port is retried.
+    ///
+    /// Returns the addresses of the apps whose configuration failed to apply,
+    /// or `None` if the daemon should shut down instead, because it was told
+    /// to or the configuration source closed meanwhile.
+    async fn roll_out(&self, config: Apps) -> Option<Vec<(ListenAddr, Protocol)>> {
+        let mut commands = self.commands_rx.lock().await;
+        let mut rollout = Box::pin(self.apply_config(config));
+        loop {
+            tokio::select! {
+                failed = &mut rollout => return Some(failed),
+                newer = self.config.config_subscriber.recv() => {
+                    let newer = newer?;
+
+                    info!("received newer configuration, cancelling rollout");
+                    rollout = Box::pin(self.apply_config(newer));
+                }
+                Some(command) = commands.recv() => match command {
+                    Command::Shutdown => {
+                        info!("cancelling rollout to shut down");
+                        return None;
+                    }
+                    Command::Reload => self.reload(),
+                },
+            }
+        }
+    }
+
+    /// Apply the configuration of every app.
+    ///
+    /// Apps whose configuration fails to apply keep serving their previous
+    /// one. With an atomic rollout, every app is prepared first, and the
+    /// whole configuration is rejected if any app fails.
+    ///
+    /// Every rollout starts a new generation, recorded on the proxies it
+    /// starts and the routes it swaps into shared listeners, so listeners
+    /// can be traced back to the configuration they serve through the
+    /// daemon's [`Metrics`].
+    ///
+    /// Returns the addresses of the apps whose configuration failed to apply.
+    async fn apply_config(&self, config: Apps) -> Vec<(ListenAddr, Protocol)> {
+        let generation = Generation {
+            number: self.generations.fetch_add(1, Ordering::Relaxed) + 1,
+            version: config.version,
+        };
+        info!(generation = as_serde!(generation); "rolling out configuration");
+        self.metrics.start_rollout(&generation);
+
+        let conflicts = shared_limit_conflicts(&config.apps);
+        if self.config.atomic_rollout {
+            return self
+                .apply_config_atomically(config.apps, &conflicts, &generation)
+                .await;
+        }
+
+        let app_update_futures = config.apps.into_iter().map(|config| async {
+            let (app, addresses) = (config.name.clone(), addresses(&config));
+            let result = match conflicts.get(&app) {
+                Some(port) => Err(DaemonError::LimitConflict(port.to_owned())),
+                None => self.apply_app_config(config, &generation).await,
+            };
+            (app, addresses, result)
+        });
+
+        let mut failed = Vec::new();
+        for (app, addresses, result) in join_all(app_update_futures).await {
+            if let Err(error) = result {
+                failed.extend(addresses);
+                // Improvement: Add support for sending events for app & target which failed
+                // which can be rendered to the end-user.
+                warn!(
+                    app_name = as_serde!(app),
+                    generation = generation.number;
+                    "failed to apply configuration, keeping previous one: {:?}", error
+                )
+            }
+        }
+
+        failed
+    }
+
+    /// Apply the configuration of every app, or none if any app fails, or
+    /// has `conflicts` with another app.
+    ///
+    /// Returns the addresses of every app if the configuration is rejected.
+    async fn apply_config_atomically(
+        &self,
+        apps: Vec<AppConfig>,
+        conflicts: &HashMap<App, ListenAddr>,
+        generation: &Generation,
+    ) -> Vec<(ListenAddr, Protocol)> {
+        let all_addresses = apps.iter().flat_map(addresses).collect::<Vec<_>>();
+        let prepare_futures = apps.into_iter().map(|config| async {
+            let app = config.name.clone();
+            let result = match conflicts.get(&app) {
+                Some(port) => Err(DaemonError::LimitConflict(port.to_owned())),
+                None => self.prepare_app(config, generation).await,
+            };
+            (app, result)
+        });
+
+        let mut prepared = Vec::new();
+        let mut failed = 0;
+        for (app, result) in join_all(prepare_futures).await {
+            match result {
+                Ok(app) => prepared.push(app),
+                Err(error) => {
+                    failed += 1;
+                    warn!(
+                        app_name = as_serde!(app),
+                        generation = generation.number;
+                        "failed to apply configuration: {:?}", error
+                    )
+                }
+            }
+        }
+
+        // Listeners bound for the other apps are closed along with them.
+        if failed > 0 {
+            warn!(
+                generation = generation.number,
+                failed_apps = failed;
+                "configuration rejected, keeping previous one"
+            );
+            return all_addresses;
+        }
+
+        let started = prepared
+            .into_iter()
+            .map(|app| {
+                let name = app.config.name.clone();
+                app.start().map_err(|error| (name, error))
+            })
+            .collect::<Result<Vec<_>, _>>();
+        match started {
+            Ok(apps) => {
+                apps.into_iter().for_each(|app| self.commit_app(app));
+                Vec::new()
+            }
+            Err((app, error)) => {
+                warn!(
+                    app_name = as_serde!(app),
+                    generation = generation.number;
+                    "failed to start proxies, configuration rejected: {:?}", error
+                );
+                all_addresses
+            }
+        }
+    }
+
+    /// Shut down every proxy at once, waiting for their sessions to drain
+    /// until the daemon's shutdown timeout.
+    ///
+    /// Improvement(s):
+    /// - Proxies retired by earlier rollouts may still be draining, and
+    ///   aren't waited for.
+    async fn shutdown(&mut self) -> ShutdownReport {
+        info!("shutting down");
+        notify(&[NotifyState::Stopping]);
+        let dedicated = drain(&self.apps)
+            .into_iter()
+            .flat_map(|(_, proxies)| proxies.into_iter().map(|(_, proxy)| proxy));
+        let shared = drain(&self.shared_listeners)
+            .into_iter()
+            .map(|(_, listener)| listener.proxy);
+
+        let deadline = Instant::now().checked_add(self.config.shutdown_timeout);
+        let shutdowns = dedicated.chain(shared).map(|proxy| async move {
+            let Some(deadline) = deadline else {
+                return proxy.shutdown().await;
+            };
+
+            // Proxies still draining at the deadline count as aborted.
+            timeout_at(deadline, proxy.shutdown())
+                .await
+                .unwrap_or(ShutdownReport {
+                    aborted: 1,
+                    ..Default::default()
+                })
+        });
+
+        let mut report = ShutdownReport::default();
+        for proxy in join_all(shutdowns).await {
+            report += proxy;
+        }
+
+        info!(report = as_serde!(report); "shut down");
+        report
+    }
+
     /// Apply application configuration to proxies.
     ///
+    /// The app's listeners are all bound before any of its proxies is
+    /// replaced, so an app whose configuration fails to apply keeps serving
+    /// its previous configuration on every port.
+    ///
     /// Improvement: At the moment we're getting the entire config and spinning up new proxy for every
     /// app and killing existing ones if any. Adding support for updating only proxies affected
     /// by the change will be a huge improvement.
-    async fn apply_app_config(&self, app_config: AppConfig) -> Result<(), DaemonError> {
-        info!(app_name = as_serde!(app_config.name); "applying new configuration");
+    async fn apply_app_config(
+        &self,
+        app_config: AppConfig,
+        generation: &Generation,
+    ) -> Result<(), DaemonError> {
+        let prepared = self.prepare_app(app_config, generation).await?;
+        self.commit_app(prepared.start()?);
+        Ok(())
+    }
+
+    /// Validate an app's configuration, and bind the listeners its proxies
+    /// need, without changing what the daemon serves: bound sockets take no
+    /// connections until the app's proxies are started.
+    ///
+    /// Improvement(s):
+    /// - UDP sockets receive datagrams as soon as they are bound, which are
+    ///   dropped if the rollout is rejected.
+    async fn prepare_app(
+        &self,
+        app_config: AppConfig,
+        generation: &Generation,
+    ) -> Result<PreparedApp, DaemonError> {
+        info!(
+            app_name = as_serde!(app_config.name),
+            generation = generation.number;
+            "applying new configuration"
+        );
+
+        validate(&app_config)?;
 
         // Improvements: Allow users decide the routing strategy from the config.
         // Improvements: If no target is resolved, it'll be good to communicate back to user.
-        let strategy = RoundRobinStrategy::new(app_config.targets);
-        let retry_option = BindSocketRetryOption::builder().build();
-        let target_resolver = Arc::new(strategy);
-
-        if let Some(app) = self.apps.get(&app_config.name) {
-            // Drop proxies that do not exist in the new configuration.
-            //
-            // Improvement(s):
-            // - Instead of this, it'll be good to get changes of what happened e.g. port 80 for
-            //   app A got deleted, port 9000 for app B was added. That way, we no longer have to
-            //   handle the diffing here.
-            app.retain(|port, _| app_config.ports.contains(port));
-        }
-
-        // Create proxy for newly added app ports.
-        for port in app_config.ports {
-            let config = ProxyConfig::builder()
-                .listener(bind_with_addr_and_port_reuse(port, retry_option)?)
-                .dns_resolver(self.config.dns_resolver.clone())
-                .target_resolver(target_resolver.clone())
-                .build();
-
-            self.apps
+        let strategy = RoundRobinStrategy::new(app_config.targets.clone());
+        let tls_terminator = match &app_config.tls {
+            Some(tls) => Some(Arc::new(TlsTerminator::new(tls)?)),
+            None => None,
+        };
+
+        let upstream_tls = match &app_config.upstream_tls {
+            Some(tls) => Some(Arc::new(UpstreamTls::new(tls)?)),
+            None => None,
+        };
+
+        // Connections on shared listeners are only attributed to the app once
+        // routed, so its limit is enforced by the route instead of the proxy.
+        // The limit is resized to the new configuration once committed.
+        let app_limit = app_config.max_connections.map(|max| {
+            self.app_limits
                 .entry(app_config.name.to_owned())
-                .or_default()
-                .insert(port, Proxy::listen(config));
+                .or_insert_with(|| AppLimit::new(max))
+                .semaphore
+                .clone()
+        });
+        let route_limit = match app_config.server_names.is_empty() {
+            true => None,
+            false => app_limit.clone(),
+        };
+
+        let access_list = match app_config.allow.is_empty() && app_config.deny.is_empty() {
+            true => None,
+            false => Some(Arc::new(AccessList::new(
+                app_config.allow.clone(),
+                app_config.deny.clone(),
+                self.metrics.access_counters(&app_config.name),
+            ))),
+        };
+
+        let bandwidth = &app_config.bandwidth;
+        let (upload_cap, download_cap) =
+            match (bandwidth.upload_per_app, bandwidth.download_per_app) {
+                (None, None) => (None, None),
+                _ => self
+                    .app_bandwidth
+                    .entry(app_config.name.to_owned())
+                    .or_default()
+                    .caps(bandwidth),
+            };
+
+        // Clients keep their buckets unless the limit changed.
+        let client_rate_limit = app_config.client_rate_limit.as_ref().map(|limit| {
+            match self.client_rate_limiters.get(&app_config.name) {
+                Some(limiter) if limiter.limit() == limit => limiter.clone(),
+                _ => Arc::new(ClientRateLimiter::new(limit)),
+            }
+        });
+
+        let route = Route::builder()
+            .app(app_config.name.to_owned())
+            .generation(generation.clone())
+            .target_resolver(Arc::new(strategy))
+            .tls_terminator(tls_terminator)
+            .upstream_tls(upstream_tls)
+            .socket_options(app_config.socket.clone())
+            .splice(app_config.splice)
+            .idle_timeout(app_config.idle_timeout)
+            .max_connection_duration(app_config.max_connection_duration)
+            .half_close_timeout(app_config.half_close_timeout)
+            .abortive_close(app_config.abortive_close)
+            .connection_limit(route_limit)
+            .access_list(access_list)
+            .client_rate_limit(client_rate_limit)
+            .upload_limit(BandwidthLimiter::new(
+                bandwidth.upload_per_connection,
+                upload_cap,
+            ))
+            .download_limit(BandwidthLimiter::new(
+                bandwidth.download_per_connection,
+                download_cap,
+            ))
+            .build();
+        let route = Arc::new(route);
+
+        let listeners = if app_config.server_names.is_empty() {
+            self.prepare_dedicated_listeners(&app_config, &route, app_limit, generation)
+                .await?
+        } else {
+            self.prepare_shared_listeners(&app_config, generation)
+                .await?
+        };
+
+        Ok(PreparedApp {
+            config: app_config,
+            route,
+            listeners,
+        })
+    }
+
+    /// Bind one proxy per address for an app that owns its ports.
+    async fn prepare_dedicated_listeners(
+        &self,
+        app_config: &AppConfig,
+        route: &Arc<Route>,
+        app_limit: Option<Arc<Semaphore>>,
+        generation: &Generation,
+    ) -> Result<PreparedListeners, DaemonError> {
+        let ports = &app_config.ports;
+        let shared = ports.iter().find(|p| self.shared_listeners.contains_key(p));
+        if let (Protocol::Tcp, Some(port)) = (app_config.protocol, shared) {
+            return Err(DaemonError::AddrConflict(port.to_owned()));
         }
 
-        Ok(())
+        let mut proxies = Vec::with_capacity(ports.len());
+        for port in ports {
+            let router = Router::App(route.clone());
+            let proxy = self
+                .prepare_proxy(app_config, port, router, app_limit.clone(), generation)
+                .await?;
+            proxies.push((port.to_owned(), proxy));
+        }
+
+        Ok(PreparedListeners::Dedicated(proxies))
+    }
+
+    /// Bind a listener shared with other apps for every port of the app that
+    /// isn't served yet.
+    ///
+    /// Listener settings of a shared port come from the app that bound it.
+    async fn prepare_shared_listeners(
+        &self,
+        app_config: &AppConfig,
+        generation: &Generation,
+    ) -> Result<PreparedListeners, DaemonError> {
+        let (app, ports) = (&app_config.name, &app_config.ports);
+        let server_names = &app_config.server_names;
+        let conflict = self
+            .apps
+            .iter()
+            .filter(|entry| entry.key() != app)
+            .find_map(|entry| {
+                ports.iter().find(|p| {
+                    entry
+                        .value()
+                        .get(p)
+                        .is_some_and(|proxy| proxy.protocol() == Protocol::Tcp)
+                })
+            });
+        if let Some(port) = conflict {
+            return Err(DaemonError::AddrConflict(port.to_owned()));
+        }
+
+        for port in ports {
+            let Some(listener) = self.shared_listeners.get(port) else {
+                continue;
+            };
+
+            let taken = server_names.iter().find(|name| {
+                listener
+                    .router
+                    .app_for(&name.to_ascii_lowercase())
+                    .is_some_and(|owner| &owner != app)
+            });
+            if let Some(name) = taken {
+                return Err(DaemonError::ServerNameConflict(name.to_owned()));
+            }
+        }
+
+        // The listener is rebound if its limits change, unless it serves other
+        // apps too.
+        let limits = ListenerLimits::of(app_config);
+        let mut listeners = Vec::new();
+        for port in ports {
+            let router = match self.shared_listeners.get(port) {
+                None => Arc::new(ServerNameRouter::default()),
+                Some(listener) if listener.limits == limits => continue,
+                Some(listener) if listener.router.routes_only(app) => listener.router.clone(),
+                Some(_) => return Err(DaemonError::LimitConflict(port.to_owned())),
+            };
+
+            let router_config = Router::ServerName(router.clone());
+            let proxy = self
+                .prepare_proxy(app_config, port, router_config, None, generation)
+                .await?;
+            listeners.push((port.to_owned(), router, proxy));
+        }
+
+        Ok(PreparedListeners::Shared(listeners))
+    }
+
+    /// Swap the proxies of a prepared app in, and shut down the ones it no
+    /// longer uses.
+    fn commit_app(&self, prepared: PreparedApp<Proxy>) {
+        let PreparedApp {
+            config,
+            route,
+            listeners,
+        } = prepared;
+        let (app, ports) = (&config.name, &config.ports);
+        let generation = &route.generation;
+
+        match config.max_connections {
+            Some(max) => {
+                if let Some(mut limit) = self.app_limits.get_mut(app) {
+                    limit.resize(max);
+                }
+            }
+            None => {
+                self.app_limits.remove(app);
+            }
+        }
+
+        if let Some(mut bandwidth) = self.app_bandwidth.get_mut(app) {
+            bandwidth.update(&config.bandwidth);
+        }
+        self.app_bandwidth
+            .remove_if(app, |_, bandwidth| bandwidth.is_empty());
+
+        match &route.client_rate_limit {
+            Some(limiter) => {
+                self.client_rate_limiters
+                    .insert(app.to_owned(), limiter.clone());
+            }
+            None => {
+                self.client_rate_limiters.remove(app);
+            }
+        }
+
+        match listeners {
+            PreparedListeners::Dedicated(proxies) => {
+                // The app may have switched from server name routing.
+                self.remove_shared_routes(app, &[], generation);
+
+                for (port, proxy) in proxies {
+                    self.metrics
+                        .update_listener(&port, config.protocol, generation);
+                    let replaced = self
+                        .apps
+                        .entry(app.to_owned())
+                        .or_default()
+                        .insert(port.to_owned(), proxy);
+                    if let Some(proxy) = replaced {
+                        retire(app, &port, proxy);
+                    }
+                }
+
+                if let Some(proxies) = self.apps.get(app) {
+                    // Shut down proxies that do not exist in the new configuration.
+                    //
+                    // Improvement(s):
+                    // - Instead of this, it'll be good to get changes of what happened e.g. port 80 for
+                    //   app A got deleted, port 9000 for app B was added. That way, we no longer have to
+                    //   handle the diffing here.
+                    let removed = proxies
+                        .iter()
+                        .map(|entry| entry.key().to_owned())
+                        .filter(|port| !ports.contains(port))
+                        .collect::<Vec<_>>();
+                    for (port, proxy) in removed.iter().filter_map(|port| proxies.remove(port)) {
+                        self.metrics.remove_listener(&port, config.protocol);
+                        retire(app, &port, proxy);
+                    }
+                }
+            }
+            PreparedListeners::Shared(listeners) => {
+                // The app may have switched from dedicated listeners.
+                if let Some((_, proxies)) = self.apps.remove(app) {
+                    for (port, proxy) in proxies {
+                        self.metrics.remove_listener(&port, proxy.protocol());
+                        retire(app, &port, proxy);
+                    }
+                }
+                self.remove_shared_routes(app, ports, generation);
+
+                // Another app may have bound a port meanwhile, in which case
+                // its listener is kept.
+                let limits = ListenerLimits::of(&config);
+                for (port, router, proxy) in listeners {
+                    let listener = SharedListener {
+                        router,
+                        proxy,
+                        limits,
+                    };
+                    match self.shared_listeners.entry(port) {
+                        Entry::Vacant(entry) => {
+                            entry.insert(listener);
+                        }
+                        Entry::Occupied(mut entry)
+                            if Arc::ptr_eq(&entry.get().router, &listener.router) =>
+                        {
+                            let replaced = entry.insert(listener);
+                            retire(app, entry.key(), replaced.proxy);
+                        }
+                        Entry::Occupied(_) => {}
+                    }
+                }
+
+                for port in ports {
+                    match self.shared_listeners.get(port) {
+                        Some(listener) => {
+                            listener
+                                .router
+                                .replace_app(route.clone(), &config.server_names);
+                            self.metrics
+                                .update_listener(port, Protocol::Tcp, generation);
+                        }
+                        // The last app on the port left while binding.
+                        None => warn!(
+                            app_name = as_serde!(app),
+                            port = as_serde!(port.to_string());
+                            "shared listener shut down while binding, port not served until next rollout"
+                        ),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Bind the listeners of an app address, and configure a proxy for them.
+    ///
+    /// Sockets handed over by a previous daemon are served first, then
+    /// sockets passed by the service manager, before binding new ones.
+    /// Binding is retried according to the daemon's retry policy, with the
+    /// app's overrides.
+    ///
+    /// Connections accepted by the proxy count against the daemon-wide
+    /// limit, the listener's limit, and `app_limit` if the app is known
+    /// before routing.
+    async fn prepare_proxy(
+        &self,
+        app_config: &AppConfig,
+        port: &ListenAddr,
+        router: Router,
+        app_limit: Option<Arc<Semaphore>>,
+        generation: &Generation,
+    ) -> Result<PreparedProxy, DaemonError> {
+        let key = (port.to_owned(), app_config.protocol);
+        // Inherited listeners are duplicated rather than taken, so they're
+        // still there to hand back if the rollout fails.
+        let inherited = self
+            .inherited
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+            .map(|fds| {
+                fds.iter()
+                    .map(OwnedFd::try_clone)
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .transpose()?;
+        let adopt = |fd| adopt_listener(fd, port, app_config.protocol).map(BoundSocket::Ready);
+        let activated = find_activated(&self.activated, port, app_config.protocol);
+        let sockets = match (inherited, activated) {
+            (Some(fds), _) => fds.into_iter().map(adopt).collect::<Result<_, _>>()?,
+            (None, Some(fd)) => {
+                if app_config.socket.acceptors > 1 {
+                    warn!(
+                        app = as_serde!(app_config.name),
+                        addr = as_serde!(port.to_string()),
+                        acceptors = app_config.socket.acceptors;
+                        "socket from service manager is served by a single acceptor"
+                    );
+                }
+                vec![adopt(fd.try_clone()?)?]
+            }
+            (None, None) => {
+                bind_listeners(
+                    port,
+                    app_config.protocol,
+                    app_config.ipv6_only,
+                    &app_config.socket,
+                    self.config
+                        .bind_socket_retry_option
+                        .with_overrides(&app_config.bind_retry),
+                )
+                .await?
+            }
+        };
+
+        let listener_limit = app_config
+            .max_connections_per_listener
+            .map(|max| Arc::new(Semaphore::new(max)));
+        let connection_limits = [self.connection_limit.clone(), app_limit, listener_limit]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let config = ProxyConfig::builder()
+            .listeners(Vec::new())
+            .dns_resolver(self.config.dns_resolver)
+            .router(router)
+            .generation(generation.clone())
+            .per_core_runtime(app_config.socket.per_core_runtime)
+            .accept_reserve_fd(app_config.socket.reserve_fd)
+            .accept_errors(
+                self.metrics
+                    .accept_error_counters(port, app_config.protocol),
+            )
+            .connection_limits(connection_limits)
+            .connection_limit_policy(app_config.connection_limit_policy)
+            .max_udp_sessions(app_config.max_udp_sessions)
+            .udp_session_limit_policy(app_config.udp_session_limit_policy)
+            .build();
+
+        Ok(PreparedProxy { config, sockets })
+    }
+
+    /// Remove the app's routes from shared listeners on ports other than
+    /// `keep_ports`, and shut down shared listeners no app routes through.
+    /// Listeners still serving other apps are recorded as updated by
+    /// `generation`.
+    fn remove_shared_routes(&self, app: &str, keep_ports: &[ListenAddr], generation: &Generation) {
+        self.shared_listeners
+            .iter()
+            .filter(|listener| !keep_ports.contains(listener.key()))
+            .filter(|listener| listener.router.remove_app(app))
+            .for_each(|listener| {
+                self.metrics
+                    .update_listener(listener.key(), Protocol::Tcp, generation)
+            });
+
+        let unused = self
+            .shared_listeners
+            .iter()
+            .filter(|listener| listener.router.is_empty())
+            .map(|listener| listener.key().to_owned())
+            .collect::<Vec<_>>();
+        for port in unused {
+            if let Some((port, listener)) = self.shared_listeners.remove(&port) {
+                self.metrics.remove_listener(&port, Protocol::Tcp);
+                retire(app, &port, listener.proxy);
+            }
+        }
+    }
+}
+
+/// Accept the next upgrade, or never if the daemon doesn't serve upgrades.
+async fn accept_upgrade(listener: Option<&UnixListener>) -> io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => pending().await,
+    }
+}
+
+/// Shut down a proxy replaced or removed by a rollout in the background, so
+/// the rollout doesn't wait for its sessions to drain.
+fn retire(app: &str, port: &ListenAddr, proxy: Proxy) {
+    let (app, port) = (app.to_owned(), port.to_owned());
+    spawn(async move {
+        let generation = proxy.generation().clone();
+        let report = proxy.shutdown().await;
+        info!(
+            app_name = as_serde!(app),
+            port = as_serde!(port.to_string()),
+            generation = as_serde!(generation),
+            report = as_serde!(report);
+            "proxy shut down"
+        );
+    });
+}
+
+/// Remove every entry of a map.
+fn drain<K: Clone + Eq + Hash, V>(map: &DashMap<K, V>) -> Vec<(K, V)> {
+    let keys = map
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect::<Vec<_>>();
+    keys.into_iter()
+        .filter_map(|key| map.remove(&key))
+        .collect()
+}
+
+/// Apps sharing a port by server name with an earlier app of the
+/// configuration that sets different listener limits, along with the port.
+fn shared_limit_conflicts(apps: &[AppConfig]) -> HashMap<App, ListenAddr> {
+    let mut limits = HashMap::new();
+    let mut conflicts = HashMap::new();
+    for app in apps.iter().filter(|app| !app.server_names.is_empty()) {
+        for port in &app.ports {
+            let first = *limits
+                .entry(port)
+                .or_insert_with(|| ListenerLimits::of(app));
+            if first != ListenerLimits::of(app) {
+                conflicts
+                    .entry(app.name.to_owned())
+                    .or_insert_with(|| port.to_owned());
+            }
+        }
+    }
+
+    conflicts
+}
+
+/// Addresses the app listens on.
+fn addresses(app_config: &AppConfig) -> Vec<(ListenAddr, Protocol)> {
+    app_config
+        .ports
+        .iter()
+        .map(|port| (port.to_owned(), app_config.protocol))
+        .collect()
+}
+
+/// Reject combinations of options that can't be served together.
+fn validate(app_config: &AppConfig) -> Result<(), DaemonError> {
+    let invalid = |reason: &str| Err(DaemonError::InvalidConfig(reason.to_owned()));
+    let unix_ports = app_config
+        .ports
+        .iter()
+        .any(|port| matches!(port, ListenAddr::Unix(_)));
+    let unix_targets = app_config
+        .targets
+        .iter()
+        .any(|target| matches!(target, TargetAddr::Unix(_)));
+
+    if app_config.protocol == Protocol::Udp
+        && (!app_config.server_names.is_empty()
+            || app_config.tls.is_some()
+            || app_config.upstream_tls.is_some())
+    {
+        return invalid("server names and tls are only supported for tcp apps");
+    }
+
+    if app_config.protocol == Protocol::Udp && (unix_ports || unix_targets) {
+        return invalid("unix sockets are only supported for tcp apps");
+    }
+
+    if !app_config.server_names.is_empty() && unix_ports {
+        return invalid("server name routing is only supported on ports");
+    }
+
+    if app_config.upstream_tls.is_some() && unix_targets {
+        return invalid("upstream tls is not supported for unix socket targets");
+    }
+
+    if let Some(limit) = &app_config.client_rate_limit {
+        if !(limit.rate.is_finite() && limit.rate > 0.0) || limit.burst == 0 {
+            return invalid("client rate limit needs a positive rate and burst");
+        }
+
+        if limit.ipv4_prefix > 32 || limit.ipv6_prefix > 128 {
+            return invalid("client rate limit prefix is longer than the address");
+        }
+    }
+
+    // Timers shorter than a millisecond fire immediately, or panic when
+    // used as the session sweep period.
+    let timeouts = [
+        app_config.idle_timeout,
+        app_config.max_connection_duration,
+        app_config.half_close_timeout,
+    ];
+    if timeouts
+        .into_iter()
+        .flatten()
+        .any(|timeout| timeout < Duration::from_millis(1))
+    {
+        return invalid("timeouts must be at least 1ms");
+    }
+
+    if [
+        app_config.max_connections,
+        app_config.max_connections_per_listener,
+    ]
+    .contains(&Some(0))
+    {
+        return invalid("connection limits must be positive");
+    }
+
+    if app_config.max_udp_sessions == 0 {
+        return invalid("max udp sessions must be positive");
+    }
+
+    if app_config.socket.acceptors == 0 {
+        return invalid("socket acceptors must be positive");
+    }
+
+    if app_config.socket.backlog < 0 {
+        return invalid("socket backlog must not be negative");
+    }
+
+    let bandwidth = &app_config.bandwidth;
+    if [
+        bandwidth.upload_per_connection,
+        bandwidth.download_per_connection,
+        bandwidth.upload_per_app,
+        bandwidth.download_per_app,
+    ]
+    .contains(&Some(0))
+    {
+        return invalid("bandwidth limits must be positive");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::validate;
+    use super::Daemon;
+    use super::DaemonConfig;
+    use super::DaemonError;
+    use crate::config::AppConfig;
+    use crate::config::Apps;
+    use crate::config::ConfigFileSubscriber;
+    use crate::config::ConfigSubscriber;
+    use crate::config::FileContext;
+    use crate::config::ListenAddr;
+    use crate::config::Protocol;
+    use crate::daemon::BindSocketRetryOption;
+    use crate::dns::default_async_dns_resolver;
+    use crate::proxy::BandwidthLimiter;
+    use crate::proxy::Generation;
+    use crate::proxy::Listener;
+    use serde_json::json;
+    use std::io::ErrorKind;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+    use tempfile::NamedTempFile;
+    use tokio::io::AsyncReadExt;
+    use tokio::task::spawn_blocking;
+
+    fn apps(apps: &[(&str, &[&str])]) -> Apps {
+        let apps = apps.iter().map(|(name, ports)| {
+            json!({
+                "Name": name,
+                "Ports": ports,
+                "Targets": ["127.0.0.1:9"],
+                "BindRetry": { "Attempts": 1 },
+            })
+        });
+        let config = json!({ "Apps": apps.collect::<Vec<_>>() });
+        serde_json::from_str(&config.to_string()).unwrap()
+    }
+
+    async fn daemon() -> (NamedTempFile, Daemon<FileContext>) {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), r#"{"Apps":[]}"#).unwrap();
+        let config = DaemonConfig::builder()
+            .config_subscriber(ConfigFileSubscriber::new(file.path()).subscribe().unwrap())
+            .bind_socket_retry_option(BindSocketRetryOption::builder().build())
+            .dns_resolver(default_async_dns_resolver().await.unwrap())
+            .build();
+        (file, Daemon::new(config).unwrap())
+    }
+
+    fn read_all(mut stream: impl Read) -> Vec<u8> {
+        let mut reply = Vec::new();
+        let _ = stream.read_to_end(&mut reply);
+        reply
+    }
+
+    fn local_addrs<C>(daemon: &Daemon<C>, app: &str) -> Vec<SocketAddr> {
+        let Some(proxies) = daemon.apps.get(app) else {
+            return Vec::new();
+        };
+        proxies
+            .iter()
+            .flat_map(|proxy| {
+                proxy
+                    .listeners()
+                    .iter()
+                    .map(|listener| match &**listener {
+                        Listener::Tcp(listener) => listener.local_addr().unwrap(),
+                        _ => unreachable!(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_failed_rollout_keeps_previous_configuration() {
+        let (_file, mut daemon) = daemon().await;
+
+        let free = "127.0.0.1:0";
+        daemon.apply_config(apps(&[("app", &[free])])).await;
+        let serving = local_addrs(&daemon, "app");
+        assert_eq!(serving.len(), 1);
+
+        // The app keeps serving its previous listener when a port is busy.
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let busy = taken.local_addr().unwrap().to_string();
+        daemon.apply_config(apps(&[("app", &[free, &busy])])).await;
+        assert_eq!(local_addrs(&daemon, "app"), serving);
+
+        // Other apps are still updated.
+        daemon
+            .apply_config(apps(&[("app", &[&busy]), ("other", &[free])]))
+            .await;
+        assert_eq!(local_addrs(&daemon, "app"), serving);
+        assert_eq!(local_addrs(&daemon, "other").len(), 1);
+
+        // With an atomic rollout, the whole configuration is rejected.
+        daemon.config.atomic_rollout = true;
+        let config = apps(&[("app", &[&busy]), ("other", &[free]), ("new", &[free])]);
+        daemon.apply_config(config).await;
+        assert_eq!(local_addrs(&daemon, "app"), serving);
+        assert!(local_addrs(&daemon, "new").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxies_record_config_generation() {
+        let (_file, daemon) = daemon().await;
+        let generation = || {
+            let proxies = daemon.apps.get("app").unwrap();
+            let proxy = proxies.iter().next().unwrap();
+            proxy.generation().clone()
+        };
+
+        let mut config = apps(&[("app", &["127.0.0.1:0"])]);
+        config.version = Some("v1".to_owned());
+        daemon.apply_config(config).await;
+        let v1 = Generation {
+            number: 1,
+            version: Some("v1".to_owned()),
+        };
+        assert_eq!(generation(), v1);
+
+        daemon
+            .apply_config(apps(&[("app", &["127.0.0.1:0"])]))
+            .await;
+        let v2 = Generation {
+            number: 2,
+            version: None,
+        };
+        assert_eq!(generation(), v2);
+
+        let metrics = daemon.handle().metrics;
+        assert_eq!(metrics.generation(), v2);
+        let port = "127.0.0.1:0".parse().unwrap();
+        let listener = (ListenAddr::Inet(port), Protocol::Tcp);
+        assert_eq!(metrics.listener_generations(), [(listener, v2)].into());
+    }
+
+    #[tokio::test]
+    async fn test_shared_listener_route_swaps_update_generation() {
+        let (_file, daemon) = daemon().await;
+        let metrics = daemon.handle().metrics;
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = |apps: &[(&str, &str)]| {
+            let apps = apps.iter().map(|(name, server_name)| {
+                json!({
+                    "Name": name,
+                    "Ports": [port.to_string()],
+                    "Targets": ["127.0.0.1:9"],
+                    "ServerNames": [server_name],
+                })
+            });
+            let config = json!({ "Apps": apps.collect::<Vec<_>>() });
+            serde_json::from_str(&config.to_string()).unwrap()
+        };
+        let listener_generation = || {
+            let listener = (ListenAddr::Inet(port), Protocol::Tcp);
+            metrics.listener_generations()[&listener].number
+        };
+
+        daemon.apply_config(config(&[("a", "a.example")])).await;
+        assert_eq!(listener_generation(), 1);
+
+        // The listener is kept, but its routes are swapped.
+        daemon
+            .apply_config(config(&[("a", "a.example"), ("b", "b.example")]))
+            .await;
+        daemon.apply_config(config(&[("b", "c.example")])).await;
+        assert_eq!(listener_generation(), 3);
+        let shared = daemon
+            .shared_listeners
+            .get(&ListenAddr::Inet(port))
+            .unwrap();
+        assert_eq!(shared.proxy.generation().number, 1);
+        assert_eq!(
+            shared
+                .router
+                .resolve("c.example")
+                .unwrap()
+                .generation
+                .number,
+            3
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rejected_rollout_leaves_previous_listeners_serving() {
+        let (_file, daemon) = daemon().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let busy = taken.local_addr().unwrap();
+
+        // Targets tell which generation a connection went through.
+        let target = |reply: &'static [u8]| {
+            let target = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = target.local_addr().unwrap().to_string();
+            std::thread::spawn(move || {
+                for mut stream in target.incoming().flatten() {
+                    let _ = stream.write_all(reply);
+                }
+            });
+            addr
+        };
+        let config = |target: String, ports: &[String]| {
+            let config = json!({
+                "Apps": [{
+                    "Name": "app",
+                    "Ports": ports,
+                    "Targets": [target],
+                    "BindRetry": { "Attempts": 3, "InitialDelay": "200ms" },
+                }],
+            });
+            serde_json::from_str::<Apps>(&config.to_string()).unwrap()
+        };
+        let unix = format!("unix:{}", path.display());
+        let ports = [port.to_string(), unix];
+        daemon.apply_config(config(target(b"old"), &ports)).await;
+
+        let served_by_old = || async {
+            let tcp = TcpStream::connect(port).unwrap();
+            let unix = UnixStream::connect(&path).unwrap();
+            tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            unix.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let replies = spawn_blocking(move || [read_all(tcp), read_all(unix)]);
+            replies.await.unwrap() == [b"old"; 2]
+        };
+
+        // The last port stays busy while the first ones are bound.
+        let ports = [ports[0].clone(), ports[1].clone(), busy.to_string()];
+        let rejected = config(target(b"new"), &ports);
+        let rollout = daemon.apply_config(rejected);
+        let clients = async {
+            for _ in 0..16 {
+                assert!(served_by_old().await);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::join!(rollout, clients);
+
+        for _ in 0..16 {
+            assert!(served_by_old().await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_upgrade_leaves_previous_daemon_serving() {
+        let (_file, mut daemon) = daemon().await;
+        let (previous, mut previous_peer) = UnixStream::pair().unwrap();
+        previous_peer.set_nonblocking(true).unwrap();
+        daemon.previous = Some(previous);
+
+        let inherited = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = inherited.local_addr().unwrap();
+        let key = (ListenAddr::Inet(addr), Protocol::Tcp);
+        daemon
+            .inherited
+            .lock()
+            .unwrap()
+            .insert(key.clone(), vec![inherited.try_clone().unwrap().into()]);
+
+        // The app serving the inherited listener is rejected along with the
+        // rest of the configuration.
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let busy = taken.local_addr().unwrap().to_string();
+        let served = addr.to_string();
+        daemon.config.atomic_rollout = true;
+        let failed = daemon
+            .apply_config(apps(&[("app", &[&served]), ("other", &[&busy])]))
+            .await;
+
+        let result = daemon.finish_take_over(&failed);
+        assert!(matches!(
+            result,
+            Err(DaemonError::UpgradeAborted(ports)) if ports == [key.0.clone()]
+        ));
+        assert!(daemon.previous.is_some());
+        assert!(daemon.inherited.lock().unwrap().contains_key(&key));
+        let mut byte = [0];
+        let error = previous_peer.read(&mut byte).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+
+        // The previous daemon's listener is still open.
+        let _client = TcpStream::connect(addr).unwrap();
+        inherited.accept().unwrap();
+
+        // Once every inherited listener is served, the previous daemon is
+        // told to shut down, even if other apps fail.
+        daemon.config.atomic_rollout = false;
+        let failed = daemon
+            .apply_config(apps(&[("app", &[&served]), ("other", &[&busy])]))
+            .await;
+        assert!(daemon.finish_take_over(&failed).is_ok());
+        assert!(daemon.previous.is_none());
+        assert!(daemon.inherited.lock().unwrap().is_empty());
+        previous_peer.set_nonblocking(false).unwrap();
+        previous_peer.read_exact(&mut byte).unwrap();
+        assert_eq!(local_addrs(&daemon, "app"), [addr]);
+    }
+
+    #[test]
+    fn test_sub_millisecond_timeouts_are_rejected() {
+        let app = |key: &str, timeout: &str| -> AppConfig {
+            let config = json!({
+                "Name": "app",
+                "Ports": ["127.0.0.1:0"],
+                "Targets": ["127.0.0.1:9"],
+                key: timeout,
+            });
+            serde_json::from_str(&config.to_string()).unwrap()
+        };
+
+        for key in ["IdleTimeout", "MaxConnectionDuration", "HalfCloseTimeout"] {
+            for timeout in ["0s", "500us"] {
+                assert!(matches!(
+                    validate(&app(key, timeout)),
+                    Err(DaemonError::InvalidConfig(_))
+                ));
+            }
+            assert!(validate(&app(key, "1ms")).is_ok());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_accept_loops_are_restarted_during_rollouts() {
+        let (_file, daemon) = daemon().await;
+        daemon
+            .apply_config(apps(&[("app", &["127.0.0.1:0"])]))
+            .await;
+        let port = daemon
+            .apps
+            .get("app")
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap()
+            .key()
+            .to_owned();
+        let metrics = daemon.handle().metrics;
+        let supervisor = daemon.spawn_supervisor();
+
+        // The next rollout keeps retrying to bind a busy port meanwhile.
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = json!({
+            "Apps": [{
+                "Name": "other",
+                "Ports": [taken.local_addr().unwrap().to_string()],
+                "Targets": ["127.0.0.1:9"],
+                "BindRetry": { "Attempts": 100, "InitialDelay": "100ms" },
+            }],
+        });
+        let config = serde_json::from_str(&config.to_string()).unwrap();
+
+        daemon
+            .apps
+            .get("app")
+            .unwrap()
+            .iter()
+            .for_each(|proxy| proxy.abort_acceptors());
+        let restarted = async {
+            while metrics.total_restarts() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::select! {
+            _ = daemon.apply_config(config) => panic!("rollout finished before the restart"),
+            () = restarted => {}
+        }
+
+        let key = (port, Protocol::Tcp);
+        assert_eq!(metrics.restarts(), [(key.clone(), 1)].into());
+        assert_eq!(metrics.accept_errors()[&key].transient(), 0);
+        supervisor.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_access_decisions_are_counted_across_rollouts() {
+        let (_file, daemon) = daemon().await;
+        let metrics = daemon.handle().metrics;
+        let config = || {
+            let config = json!({
+                "Apps": [{
+                    "Name": "app",
+                    "Ports": ["127.0.0.1:0"],
+                    "Targets": ["127.0.0.1:9"],
+                    "Allow": ["10.0.0.0/8"],
+                }],
+            });
+            serde_json::from_str(&config.to_string()).unwrap()
+        };
+
+        for denied in 1..=2 {
+            daemon.apply_config(config()).await;
+            let client = TcpStream::connect(local_addrs(&daemon, "app")[0]).unwrap();
+            assert!(spawn_blocking(|| read_all(client))
+                .await
+                .unwrap()
+                .is_empty());
+            assert_eq!(metrics.access_decisions()["app"].denied(), denied);
+        }
+    }
+
+    #[test]
+    fn test_sample_config_is_valid() {
+        let config: Apps = serde_json::from_str(include_str!("../../../config.json")).unwrap();
+        for app in &config.apps {
+            validate(app).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_zero_connection_limits_are_rejected() {
+        for key in [
+            "MaxConnections",
+            "MaxConnectionsPerListener",
+            "MaxUdpSessions",
+        ] {
+            let config = json!({
+                "Name": "app",
+                "Ports": ["127.0.0.1:0"],
+                "Targets": ["127.0.0.1:9"],
+                key: 0,
+            });
+            let app = serde_json::from_str(&config.to_string()).unwrap();
+            assert!(matches!(validate(&app), Err(DaemonError::InvalidConfig(_))));
+        }
+    }
+
+    #[test]
+    fn test_invalid_socket_options_are_rejected() {
+        for socket in [json!({ "Acceptors": 0 }), json!({ "Backlog": -1 })] {
+            let config = json!({
+                "Name": "app",
+                "Ports": ["127.0.0.1:0"],
+                "Targets": ["127.0.0.1:9"],
+                "Socket": socket,
+            });
+            let app = serde_json::from_str(&config.to_string()).unwrap();
+            assert!(matches!(validate(&app), Err(DaemonError::InvalidConfig(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_listener_limits_must_agree() {
+        let (_file, daemon) = daemon().await;
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = |apps: &[(&str, usize)]| {
+            let apps = apps.iter().map(|(name, max)| {
+                json!({
+                    "Name": name,
+                    "Ports": [port.to_string()],
+                    "Targets": ["127.0.0.1:9"],
+                    "ServerNames": [format!("{name}.example")],
+                    "MaxConnectionsPerListener": max,
+                })
+            });
+            let config = json!({ "Apps": apps.collect::<Vec<_>>() });
+            serde_json::from_str(&config.to_string()).unwrap()
+        };
+        let routed = |name: &str| {
+            let listener = daemon.shared_listeners.get(&ListenAddr::Inet(port));
+            listener.is_some_and(|listener| listener.router.resolve(name).is_some())
+        };
+
+        // Apps of the same configuration conflict.
+        let failed = daemon.apply_config(config(&[("a", 10), ("b", 20)])).await;
+        assert_eq!(failed, [(ListenAddr::Inet(port), Protocol::Tcp)]);
+        assert!(routed("a.example"));
+        assert!(!routed("b.example"));
+
+        // So do apps joining a listener.
+        daemon.apply_config(config(&[("b", 20)])).await;
+        assert!(!routed("b.example"));
+        daemon.apply_config(config(&[("b", 10)])).await;
+        assert!(routed("b.example"));
+    }
+
+    #[tokio::test]
+    async fn test_app_connection_limit_is_carried_over() {
+        let (_file, daemon) = daemon().await;
+        let config = |max: usize| {
+            let config = json!({
+                "Apps": [{
+                    "Name": "app",
+                    "Ports": ["127.0.0.1:0"],
+                    "Targets": ["127.0.0.1:9"],
+                    "MaxConnections": max,
+                }],
+            });
+            serde_json::from_str(&config.to_string()).unwrap()
+        };
+        let semaphore = || daemon.app_limits.get("app").unwrap().semaphore.clone();
+
+        daemon.apply_config(config(2)).await;
+        let limit = semaphore();
+        let _open = limit.clone().try_acquire_owned().unwrap();
+
+        // Connections still open count against the next configuration.
+        daemon.apply_config(config(3)).await;
+        assert!(Arc::ptr_eq(&limit, &semaphore()));
+        assert_eq!(limit.available_permits(), 2);
+
+        daemon.apply_config(config(1)).await;
+        tokio::task::yield_now().await;
+        assert_eq!(limit.available_permits(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_app_bandwidth_cap_is_carried_over() {
+        const RATE: u64 = 256 * 1024;
+        let (_file, daemon) = daemon().await;
+        let config = |rate: Option<u64>| {
+            let config = json!({
+                "Apps": [{
+                    "Name": "app",
+                    "Ports": ["127.0.0.1:0"],
+                    "Targets": ["127.0.0.1:9"],
+                    "Bandwidth": { "DownloadPerApp": rate },
+                }],
+            });
+            serde_json::from_str(&config.to_string()).unwrap()
+        };
+        let download = |len: usize| {
+            let cap = daemon.app_bandwidth.get("app").unwrap().download.clone();
+            async move {
+                let data = vec![0; len];
+                let limiter = BandwidthLimiter::new(None, cap);
+                let begin = Instant::now();
+                let mut read = Vec::new();
+                limiter
+                    .throttle(&data[..])
+                    .read_to_end(&mut read)
+                    .await
+                    .unwrap();
+                begin.elapsed()
+            }
+        };
+
+        daemon.apply_config(config(Some(RATE))).await;
+        assert!(download(RATE as usize).await < Duration::from_millis(100));
+
+        // Bytes sent before the reload count against the next configuration.
+        daemon.apply_config(config(Some(RATE))).await;
+        assert!(download(RATE as usize / 4).await >= Duration::from_millis(200));
+
+        daemon.apply_config(config(None)).await;
+        assert!(daemon.app_bandwidth.get("app").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_rate_limiter_is_carried_over() {
+        let (_file, daemon) = daemon().await;
+        let config = |burst: u32| {
+            let config = json!({
+                "Apps": [{
+                    "Name": "app",
+                    "Ports": ["127.0.0.1:0"],
+                    "Targets": ["127.0.0.1:9"],
+                    "ClientRateLimit": { "Rate": 1, "Burst": burst },
+                }],
+            });
+            serde_json::from_str(&config.to_string()).unwrap()
+        };
+        let limiter = || daemon.client_rate_limiters.get("app").unwrap().clone();
+        let client = "192.0.2.1".parse().unwrap();
+
+        daemon.apply_config(config(1)).await;
+        assert!(limiter().try_acquire(client));
+
+        // Clients stay limited while the limit is unchanged.
+        daemon.apply_config(config(1)).await;
+        assert!(!limiter().try_acquire(client));
+
+        daemon.apply_config(config(2)).await;
+        assert!(limiter().try_acquire(client));
     }
 }
@@ -0,0 +1,104 @@
+use crate::config::ListenAddr;
+use crate::daemon::Metrics;
+use crate::proxy::Proxy;
+use log::as_serde;
+use log::log;
+use log::warn;
+use log::Level;
+use std::time::Duration;
+
+/// How often proxies are checked for accept loops that stopped.
+pub(crate) const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before the second restart in a row of an accept loop, doubled on
+/// every further restart.
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between restarts of an accept loop.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// Accept loops serving this long before stopping are restarted right
+/// away, as if they never failed before.
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+/// Restart the accept loops of a proxy that stopped although the proxy
+/// wasn't shut down, e.g. because they panicked.
+///
+/// Only the failed accept loop is restarted, serving the same listener and
+/// route, i.e. the last configuration applied to the app. Accept loops that
+/// keep failing are restarted with exponential back-off. Every restart is
+/// logged, and counted in `metrics`.
+pub(crate) fn supervise(
+    app: Option<&str>,
+    port: &ListenAddr,
+    proxy: &mut Proxy,
+    metrics: &Metrics,
+) {
+    for failed in proxy.failed_acceptors() {
+        let restarts = match failed.uptime >= STABLE_UPTIME {
+            true => 0,
+            false => failed.restarts,
+        };
+        if failed.downtime < restart_delay(restarts) {
+            continue;
+        }
+
+        let Some(outcome) = proxy.restart_acceptor(failed.listener, restarts + 1) else {
+            warn!(
+                app_name = as_serde!(app),
+                port = as_serde!(port.to_string()),
+                listener = failed.listener;
+                "failed accept loop didn't report its outcome, retrying"
+            );
+            continue;
+        };
+        metrics.count_restart(port, proxy.protocol());
+
+        let reason = match outcome {
+            Ok(_) => "exited".to_owned(),
+            Err(error) => error.to_string(),
+        };
+
+        // Accept loops failing again shortly after a restart need attention.
+        let level = match restarts {
+            0 => Level::Warn,
+            _ => Level::Error,
+        };
+        log!(
+            level,
+            app_name = as_serde!(app),
+            port = as_serde!(port.to_string()),
+            listener = failed.listener,
+            generation = as_serde!(proxy.generation()),
+            uptime_ms = failed.uptime.as_millis() as u64,
+            restarts = restarts + 1,
+            total = metrics.total_restarts();
+            "restarted failed accept loop: {reason}"
+        );
+    }
+}
+
+/// Time to wait before restarting an accept loop already restarted
+/// `restarts` times in a row.
+fn restart_delay(restarts: u32) -> Duration {
+    match restarts {
+        0 => Duration::ZERO,
+        n => MIN_RESTART_DELAY
+            .saturating_mul(2u32.saturating_pow(n - 1))
+            .min(MAX_RESTART_DELAY),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::restart_delay;
+    use std::time::Duration;
+
+    #[test]
+    fn test_restart_delay_backs_off() {
+        let delays = (0..8).map(restart_delay).collect::<Vec<_>>();
+        let secs = |secs| Duration::from_secs(secs);
+        assert_eq!(delays, [0, 1, 2, 4, 8, 16, 30, 30].map(secs).to_vec());
+        assert_eq!(restart_delay(u32::MAX), secs(30));
+    }
+}
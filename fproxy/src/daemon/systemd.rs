@@ -0,0 +1,245 @@
+use crate::config::ListenAddr;
+use crate::config::Protocol;
+use log::as_serde;
+use log::info;
+use log::warn;
+use sd_notify::NotifyState;
+use socket2::SockRef;
+use socket2::Type;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write;
+use std::io;
+use std::io::ErrorKind;
+use std::mem::ManuallyDrop;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+
+/// Listening sockets passed by the service manager, by address and protocol.
+pub(crate) type Activated = HashMap<(ListenAddr, Protocol), OwnedFd>;
+
+/// Take the listening sockets passed by the service manager through
+/// `LISTEN_FDS` (socket activation).
+///
+/// Sockets are matched to app ports by the address they are bound to, so
+/// the socket units don't need to follow a naming scheme. Names from
+/// `LISTEN_FDNAMES` are only used to report sockets that can't be matched.
+///
+/// Each socket is served by a single acceptor, whatever the app's number of
+/// acceptors is.
+pub(crate) fn activated_listeners() -> io::Result<Activated> {
+    let mut listeners = Activated::new();
+    for (fd, name) in sd_notify::listen_fds_with_names(true)? {
+        // SAFETY: the service manager passes the sockets to this process,
+        // and the environment describing them was just unset, so nothing
+        // else takes ownership of them.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        match socket_key(&fd) {
+            Ok(key) => {
+                info!(
+                    name = as_serde!(name),
+                    addr = as_serde!(key.0.to_string()),
+                    protocol = as_serde!(key.1);
+                    "adopting socket from service manager"
+                );
+                listeners.insert(key, fd);
+            }
+            Err(error) => warn!(
+                name = as_serde!(name);
+                "ignoring socket from service manager: {error}"
+            ),
+        }
+    }
+
+    Ok(listeners)
+}
+
+/// Socket passed by the service manager for an app port.
+///
+/// Sockets are matched by their exact address, except that a port on an
+/// unspecified address also matches a socket bound to the same port on the
+/// unspecified address of the other family: a bare port is bound on
+/// `0.0.0.0`, while socket units given a bare port listen on `[::]`.
+pub(crate) fn find_activated<'a>(
+    activated: &'a Activated,
+    addr: &ListenAddr,
+    protocol: Protocol,
+) -> Option<&'a OwnedFd> {
+    if let Some(fd) = activated.get(&(addr.to_owned(), protocol)) {
+        return Some(fd);
+    }
+
+    let ListenAddr::Inet(addr) = addr else {
+        return None;
+    };
+    if !addr.ip().is_unspecified() {
+        return None;
+    }
+
+    activated.iter().find_map(|(key, fd)| match key {
+        (ListenAddr::Inet(other), other_protocol)
+            if *other_protocol == protocol
+                && other.port() == addr.port()
+                && other.ip().is_unspecified() =>
+        {
+            Some(fd)
+        }
+        _ => None,
+    })
+}
+
+/// Address and protocol of a listening socket.
+fn socket_key(fd: &OwnedFd) -> io::Result<(ListenAddr, Protocol)> {
+    let invalid = |reason| io::Error::new(ErrorKind::InvalidInput, reason);
+    let socket = SockRef::from(fd);
+    let addr = match socket.local_addr()?.as_socket() {
+        Some(addr) => ListenAddr::Inet(addr),
+        None => {
+            // SAFETY: the listener is never dropped, so it doesn't close the
+            // socket it borrows.
+            let listener = ManuallyDrop::new(unsafe { UnixListener::from_raw_fd(fd.as_raw_fd()) });
+            match listener.local_addr()?.as_pathname() {
+                Some(path) => ListenAddr::Unix(path.to_owned()),
+                None => return Err(invalid("unnamed socket")),
+            }
+        }
+    };
+
+    let protocol = match (socket.r#type()?, &addr) {
+        (Type::STREAM, _) => Protocol::Tcp,
+        (Type::DGRAM, ListenAddr::Inet(_)) => Protocol::Udp,
+        _ => return Err(invalid("unsupported socket type")),
+    };
+
+    Ok((addr, protocol))
+}
+
+/// Tell the service manager about a state change, if the daemon runs under
+/// one (`NOTIFY_SOCKET`).
+pub(crate) fn notify(state: &[NotifyState]) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(error) = send_notification(Path::new(&socket), state) {
+        warn!("failed to notify service manager: {error}");
+    }
+}
+
+/// Tell the service manager the daemon is reloading its configuration.
+pub(crate) fn notify_reloading() {
+    match reloading() {
+        Ok(state) => notify(&state),
+        Err(error) => warn!("failed to read monotonic time: {error}"),
+    }
+}
+
+/// State changes announcing a configuration reload.
+fn reloading() -> io::Result<[NotifyState<'static>; 2]> {
+    Ok([NotifyState::Reloading, NotifyState::monotonic_usec_now()?])
+}
+
+/// Send state changes to the service manager's notification socket.
+fn send_notification(socket: &Path, state: &[NotifyState]) -> io::Result<()> {
+    let mut message = String::new();
+    for state in state {
+        let _ = writeln!(message, "{state}");
+    }
+
+    let len = UnixDatagram::unbound()?.send_to(message.as_bytes(), socket)?;
+    if len != message.len() {
+        return Err(io::Error::new(
+            ErrorKind::WriteZero,
+            "incomplete notification",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Keep the service manager's watchdog (`WATCHDOG_USEC`) fed, at half its
+/// timeout, until the returned task is aborted.
+pub(crate) fn spawn_watchdog() -> Option<JoinHandle<()>> {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) || usec == 0 {
+        return None;
+    }
+
+    let mut ticks = interval(Duration::from_micros(usec) / 2);
+    Some(tokio::spawn(async move {
+        loop {
+            ticks.tick().await;
+            notify(&[NotifyState::Watchdog]);
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::find_activated;
+    use super::reloading;
+    use super::send_notification;
+    use super::socket_key;
+    use super::Activated;
+    use crate::config::ListenAddr;
+    use crate::config::Protocol;
+    use sd_notify::NotifyState;
+    use std::net::TcpListener;
+    use std::net::UdpSocket;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_sockets_are_matched_by_address() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = ListenAddr::Inet(tcp.local_addr().unwrap());
+        let key = socket_key(&OwnedFd::from(tcp)).unwrap();
+        assert_eq!(key, (addr, Protocol::Tcp));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = ListenAddr::Inet(udp.local_addr().unwrap());
+        let key = socket_key(&OwnedFd::from(udp)).unwrap();
+        assert_eq!(key, (addr, Protocol::Udp));
+    }
+
+    #[test]
+    fn test_unspecified_addresses_are_matched_by_port() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ipv6_any = ListenAddr::Inet("[::]:443".parse().unwrap());
+        let activated = Activated::from([((ipv6_any, Protocol::Tcp), OwnedFd::from(tcp))]);
+
+        let find = |addr: &str, protocol| {
+            let addr = ListenAddr::Inet(addr.parse().unwrap());
+            find_activated(&activated, &addr, protocol).is_some()
+        };
+        assert!(find("[::]:443", Protocol::Tcp));
+        assert!(find("0.0.0.0:443", Protocol::Tcp));
+        assert!(!find("0.0.0.0:443", Protocol::Udp));
+        assert!(!find("0.0.0.0:80", Protocol::Tcp));
+        assert!(!find("127.0.0.1:443", Protocol::Tcp));
+    }
+
+    #[test]
+    fn test_state_changes_are_notified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        let mut buf = [0; 256];
+        send_notification(&path, &[NotifyState::Ready]).unwrap();
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\n");
+
+        send_notification(&path, &reloading().unwrap()).unwrap();
+        let len = manager.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(message.starts_with("RELOADING=1\nMONOTONIC_USEC="));
+    }
+}
@@ -0,0 +1,300 @@
+use crate::config::ListenAddr;
+use crate::config::Protocol;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+/// Most sockets attached to a single frame, below the kernel's limit of 253.
+const MAX_FDS_PER_FRAME: usize = 250;
+
+/// Byte sent by the new daemon once it serves the sockets handed over.
+const READY: u8 = b'R';
+
+/// Max time to wait for the old daemon to send its sockets.
+const TAKE_OVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listening sockets of a daemon, by address and protocol, with one socket
+/// per acceptor.
+pub(crate) type HandedOver = HashMap<(ListenAddr, Protocol), Vec<OwnedFd>>;
+
+/// Header of a frame.
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame {
+    #[serde(rename = "Addr")]
+    addr: ListenAddr,
+
+    #[serde(rename = "Protocol")]
+    protocol: Protocol,
+}
+
+/// Send listening sockets to a new daemon, and wait until it serves them.
+///
+/// Sockets are sent as a sequence of frames: a length prefixed JSON header
+/// naming the address and protocol, with the sockets attached
+/// (`SCM_RIGHTS`). An empty frame ends the sequence. Once the new daemon
+/// serves the sockets, it replies with a single ready byte.
+///
+/// Both daemons accept from the same sockets until the old one shuts down,
+/// so connections queued on them are never lost.
+pub(crate) fn hand_over(
+    stream: &mut UnixStream,
+    listeners: HandedOver,
+    ready_timeout: Duration,
+) -> io::Result<()> {
+    for ((addr, protocol), fds) in listeners {
+        let frame = Frame { addr, protocol };
+        for fds in fds.chunks(MAX_FDS_PER_FRAME) {
+            send_frame(stream, Some(&frame), fds)?;
+        }
+    }
+
+    send_frame(stream, None, &[])?;
+
+    stream.set_read_timeout(Some(ready_timeout))?;
+    let mut ready = [0];
+    stream.read_exact(&mut ready)?;
+    match ready[0] {
+        READY => Ok(()),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "unexpected reply from new daemon",
+        )),
+    }
+}
+
+/// Connect to the upgrade socket of a running daemon, and take over its
+/// listening sockets.
+///
+/// Returns `None` if no daemon listens on the upgrade socket. The returned
+/// stream is used to tell the old daemon once the sockets are served.
+pub(crate) fn take_over(path: &Path) -> io::Result<Option<(UnixStream, HandedOver)>> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(error)
+            if matches!(
+                error.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(error) => return Err(error),
+    };
+
+    stream.set_read_timeout(Some(TAKE_OVER_TIMEOUT))?;
+    let mut listeners = HandedOver::new();
+    while let Some((frame, fds)) = recv_frame(&mut stream)? {
+        listeners
+            .entry((frame.addr, frame.protocol))
+            .or_default()
+            .extend(fds);
+    }
+
+    Ok(Some((stream, listeners)))
+}
+
+/// Tell the old daemon the sockets it handed over are served.
+pub(crate) fn notify_ready(stream: &mut UnixStream) -> io::Result<()> {
+    stream.write_all(&[READY])
+}
+
+/// Send a frame, with the sockets attached to its first byte. `None` sends
+/// the empty frame ending the sequence.
+fn send_frame(stream: &mut UnixStream, frame: Option<&Frame>, fds: &[OwnedFd]) -> io::Result<()> {
+    let body = match frame {
+        Some(frame) => serde_json::to_vec(frame)?,
+        None => Vec::new(),
+    };
+
+    let mut data = (body.len() as u32).to_be_bytes().to_vec();
+    data.extend(body);
+
+    let fds = fds.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+    let fds_len = mem::size_of_val(fds.as_slice());
+    let mut control = control_buffer(fds.len());
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr().cast(),
+        iov_len: data.len(),
+    };
+
+    // SAFETY: an all-zero msghdr is valid, and every pointer set on it
+    // outlives the sendmsg call. The control buffer is aligned, and sized
+    // for a header carrying `fds`.
+    let sent = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !fds.is_empty() {
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = libc::CMSG_SPACE(fds_len as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr().cast::<u8>(), libc::CMSG_DATA(cmsg), fds_len);
+        }
+
+        libc::sendmsg(stream.as_raw_fd(), &msg, SEND_FLAGS)
+    };
+
+    if sent == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    stream.write_all(&data[sent as usize..])
+}
+
+/// Receive a frame along with the sockets attached to it, or `None` once
+/// the sequence ended.
+fn recv_frame(stream: &mut UnixStream) -> io::Result<Option<(Frame, Vec<OwnedFd>)>> {
+    let mut len = [0; 4];
+    let mut control = control_buffer(MAX_FDS_PER_FRAME);
+    let mut iov = libc::iovec {
+        iov_base: len.as_mut_ptr().cast(),
+        iov_len: len.len(),
+    };
+
+    // SAFETY: an all-zero msghdr is valid, and every pointer set on it
+    // outlives the recvmsg call.
+    let (received, msg) = unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(control.as_slice()) as _;
+        let received = libc::recvmsg(stream.as_raw_fd(), &mut msg, RECV_FLAGS);
+        (received, msg)
+    };
+
+    if received == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Take ownership of the sockets first, so they are closed on error.
+    let fds = received_fds(&msg);
+    if received == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "too many sockets attached to frame",
+        ));
+    }
+
+    stream.read_exact(&mut len[received as usize..])?;
+    let mut body = vec![0; u32::from_be_bytes(len) as usize];
+    if body.is_empty() {
+        return Ok(None);
+    }
+
+    stream.read_exact(&mut body)?;
+    let frame = serde_json::from_slice(&body)?;
+    Ok(Some((frame, fds)))
+}
+
+/// Sockets attached to a received message.
+fn received_fds(msg: &libc::msghdr) -> Vec<OwnedFd> {
+    let mut fds = Vec::new();
+
+    // SAFETY: the control buffer was filled by recvmsg, which sets the
+    // length of every header within it.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+    }
+
+    fds
+}
+
+/// Control buffer for a message carrying up to `fds` sockets, aligned for
+/// control message headers.
+fn control_buffer(fds: usize) -> Vec<u64> {
+    // SAFETY: CMSG_SPACE only computes a length.
+    let space = unsafe { libc::CMSG_SPACE((fds * mem::size_of::<RawFd>()) as u32) } as usize;
+    vec![0; space.div_ceil(mem::size_of::<u64>())]
+}
+
+#[cfg(target_os = "linux")]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+
+#[cfg(not(target_os = "linux"))]
+const SEND_FLAGS: libc::c_int = 0;
+
+#[cfg(target_os = "linux")]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+
+#[cfg(not(target_os = "linux"))]
+const RECV_FLAGS: libc::c_int = 0;
+
+#[cfg(test)]
+mod test {
+    use super::hand_over;
+    use super::notify_ready;
+    use super::recv_frame;
+    use super::HandedOver;
+    use crate::config::ListenAddr;
+    use crate::config::Protocol;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::os::fd::OwnedFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    #[test]
+    fn test_listeners_are_handed_over() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = ListenAddr::Inet(listener.local_addr().unwrap());
+        let (mut old, mut new) = UnixStream::pair().unwrap();
+
+        let mut listeners = HandedOver::new();
+        let fd = OwnedFd::from(listener.try_clone().unwrap());
+        listeners.insert((addr.clone(), Protocol::Tcp), vec![fd]);
+        let old =
+            std::thread::spawn(move || hand_over(&mut old, listeners, Duration::from_secs(5)));
+
+        let (frame, mut fds) = recv_frame(&mut new).unwrap().unwrap();
+        assert_eq!((frame.addr, frame.protocol), (addr, Protocol::Tcp));
+        assert!(recv_frame(&mut new).unwrap().is_none());
+
+        // Connections to the old socket are accepted from the new one.
+        let adopted = TcpListener::from(fds.remove(0));
+        drop(listener);
+        let mut client = TcpStream::connect(adopted.local_addr().unwrap()).unwrap();
+        let (mut accepted, _) = adopted.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        notify_ready(&mut new).unwrap();
+        old.join().unwrap().unwrap();
+    }
+}
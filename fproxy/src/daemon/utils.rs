@@ -1,79 +1,376 @@
+use crate::config::BindRetry;
+use crate::config::ListenAddr;
+use crate::config::Protocol as AppProtocol;
+use crate::config::SocketOptions;
+use crate::proxy::listen_with_options;
+use crate::proxy::Listener;
 use crate::DaemonError;
+use log::warn;
+use rand::thread_rng;
+use rand::Rng;
 use socket2::Domain;
 use socket2::Protocol;
 use socket2::Socket;
 use socket2::Type;
 use std::fmt::Debug;
+use std::fs;
+use std::io;
 use std::net::SocketAddr;
-use std::thread::sleep;
+use std::os::fd::OwnedFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::net::UdpSocket;
+use tokio::net::UnixListener;
+use tokio::time::sleep;
 use typed_builder::TypedBuilder;
 
+/// Retry policy for binding sockets, with jittered exponential back-off.
 #[derive(Copy, Clone, TypedBuilder, Debug)]
 pub struct BindSocketRetryOption {
-    /// Maximum retry attempts before returning the last occurred error.
+    /// Maximum attempts, including the first one, before returning the last
+    /// occurred error.
     #[builder(default = 10)]
     pub attempts: u8,
 
-    /// Max delay duration before resetting the duration to the
-    /// `delay_increment_duration`.
+    /// Cap on the back-off between attempts. Delays are jittered down to as
+    /// little as half the back-off, so they never exceed it, but may be as
+    /// short as half of it.
     #[builder(default = Duration::from_secs(40))]
     pub max_delay_duration: Duration,
 
-    /// Duration to add after each failed retry attempt.
+    /// Back-off before the first retry, doubled after each further failed
+    /// attempt. Jittered like every delay, so the first retry happens after
+    /// half to all of it.
     #[builder(default = Duration::from_secs(10))]
     pub increment_duration: Duration,
 }
 
-/// Bind port to 0.0.0.0 while enabling address and port re-use.
+impl BindSocketRetryOption {
+    /// Apply an app's overrides on top of the policy.
+    pub fn with_overrides(self, overrides: &BindRetry) -> Self {
+        Self {
+            attempts: overrides.attempts.unwrap_or(self.attempts),
+            max_delay_duration: overrides.max_delay.unwrap_or(self.max_delay_duration),
+            increment_duration: overrides.initial_delay.unwrap_or(self.increment_duration),
+        }
+    }
+
+    /// Delay before retrying after `failures` failed attempts, between half
+    /// and all of the exponential back-off so retries of multiple sockets
+    /// are spread out.
+    fn delay(&self, failures: u32) -> Duration {
+        let backoff = self
+            .increment_duration
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_delay_duration);
+        backoff.mul_f64(thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Socket bound for an app address, that takes no connections until it's
+/// activated, so a rollout can bind every socket it needs before changing
+/// what the daemon serves.
+pub(crate) enum BoundSocket {
+    /// Listener serving as is: sockets handed over by a previous daemon or
+    /// the service manager, and UDP sockets, which receive datagrams as soon
+    /// as they are bound.
+    Ready(Listener),
+
+    /// TCP socket bound to its port, which only joins the port's re-use
+    /// group, and takes connections, once listening.
+    Tcp(Socket, SocketOptions),
+
+    /// Unix socket listening on a staging path, renamed over its path once
+    /// activated.
+    Unix(StagedUnixListener),
+}
+
+impl BoundSocket {
+    /// Start taking connections.
+    pub(crate) fn activate(self) -> Result<Listener, DaemonError> {
+        Ok(match self {
+            Self::Ready(listener) => listener,
+            Self::Tcp(socket, options) => {
+                listen_with_options(&socket, &options)?;
+                Listener::Tcp(TcpListener::from_std(socket.into())?)
+            }
+            Self::Unix(staged) => Listener::Unix(staged.activate()?),
+        })
+    }
+}
+
+/// Unix socket listening on a staging path next to the path it replaces.
+/// The staging path is removed if the socket is dropped before activation.
+pub(crate) struct StagedUnixListener {
+    listener: Option<UnixListener>,
+    staging: PathBuf,
+    path: PathBuf,
+}
+
+impl StagedUnixListener {
+    /// Rename the socket over its path, so new connections reach it.
+    fn activate(mut self) -> io::Result<UnixListener> {
+        fs::rename(&self.staging, &self.path)?;
+        Ok(self
+            .listener
+            .take()
+            .expect("staged listener activated twice"))
+    }
+}
+
+impl Drop for StagedUnixListener {
+    fn drop(&mut self) {
+        if self.listener.is_some() {
+            let _ = fs::remove_file(&self.staging);
+        }
+    }
+}
+
+/// Bind to the address while enabling address and port re-use.
 /// Enabling port re-use means we can bind both old and new instance
 /// of a listener to the same ip and port, which means we can run
 /// both old and new versions of the proxy in parallel and gradcefully
 /// shutdown the old proxy instance after all pending requests have been
 /// services.
-pub(crate) fn bind_with_addr_and_port_reuse(
-    port: u16,
+///
+/// Across daemon upgrades, listeners are handed over to the new process
+/// instead of being bound again, so connections queued on them survive.
+///
+/// `ipv6_only` controls whether an IPv6 address also accepts IPv4 clients,
+/// and is left to the system default when unset. The socket isn't
+/// listening yet.
+pub(crate) async fn bind_with_addr_and_port_reuse(
+    address: SocketAddr,
+    ipv6_only: Option<bool>,
     retry_option: BindSocketRetryOption,
-) -> Result<TcpListener, DaemonError> {
-    let address = format!("0.0.0.0:{}", port).parse::<SocketAddr>()?;
+) -> Result<Socket, DaemonError> {
+    bind_reusable(
+        address,
+        ipv6_only,
+        Type::STREAM,
+        Protocol::TCP,
+        retry_option,
+    )
+    .await
+}
+
+/// UDP counterpart of [`bind_with_addr_and_port_reuse`]. With port re-use,
+/// the kernel spreads datagrams between the old and new socket by source
+/// address until the old proxy is shut down.
+pub(crate) async fn bind_udp_with_addr_and_port_reuse(
+    address: SocketAddr,
+    ipv6_only: Option<bool>,
+    retry_option: BindSocketRetryOption,
+) -> Result<UdpSocket, DaemonError> {
+    let socket =
+        bind_reusable(address, ipv6_only, Type::DGRAM, Protocol::UDP, retry_option).await?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+async fn bind_reusable(
+    address: SocketAddr,
+    ipv6_only: Option<bool>,
+    ty: Type,
+    protocol: Protocol,
+    retry_option: BindSocketRetryOption,
+) -> Result<Socket, DaemonError> {
     let domain = Domain::for_address(address);
-    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    let socket = Socket::new(domain, ty, Some(protocol))?;
     socket.set_reuse_address(true)?;
     socket.set_reuse_port(true)?;
+    if let (Some(only_v6), SocketAddr::V6(_)) = (ipv6_only, address) {
+        socket.set_only_v6(only_v6)?;
+    }
 
-    retry(|| socket.bind(&(address).into()), retry_option)?;
+    retry(|| socket.bind(&(address).into()), retry_option).await?;
     socket.set_nonblocking(true)?;
-    socket.listen(128)?;
-    Ok(TcpListener::from_std(socket.into())?)
+    Ok(socket)
 }
 
-/// Naive implementation for retrying an operation until
-/// it is either successful, or the retries count is 0.
+/// Bind the listening sockets for an app address, one per acceptor.
 ///
-/// Retry interval = min(max_delay, previous interval + delay increment)
-fn retry<F, T, E>(op: F, mut option: BindSocketRetryOption) -> Result<T, E>
+/// Sockets bound to a port share it through port re-use, so the kernel
+/// spreads connections between them. Unix sockets can't be shared, and are
+/// always served by a single acceptor.
+pub(crate) async fn bind_listeners(
+    addr: &ListenAddr,
+    protocol: AppProtocol,
+    ipv6_only: Option<bool>,
+    options: &SocketOptions,
+    retry_option: BindSocketRetryOption,
+) -> Result<Vec<BoundSocket>, DaemonError> {
+    let acceptors = match addr {
+        ListenAddr::Inet(_) => options.acceptors,
+        ListenAddr::Unix(_) => 1,
+    };
+
+    let mut listeners = Vec::with_capacity(acceptors);
+    for _ in 0..acceptors {
+        listeners.push(bind_listener(addr, protocol, ipv6_only, options, retry_option).await?);
+    }
+
+    Ok(listeners)
+}
+
+/// Bind a listening socket for an app address.
+async fn bind_listener(
+    addr: &ListenAddr,
+    protocol: AppProtocol,
+    ipv6_only: Option<bool>,
+    options: &SocketOptions,
+    retry_option: BindSocketRetryOption,
+) -> Result<BoundSocket, DaemonError> {
+    Ok(match (addr, protocol) {
+        (ListenAddr::Inet(address), AppProtocol::Tcp) => BoundSocket::Tcp(
+            bind_with_addr_and_port_reuse(*address, ipv6_only, retry_option).await?,
+            options.clone(),
+        ),
+        (ListenAddr::Inet(address), AppProtocol::Udp) => BoundSocket::Ready(Listener::Udp(
+            Arc::new(bind_udp_with_addr_and_port_reuse(*address, ipv6_only, retry_option).await?),
+        )),
+        (ListenAddr::Unix(path), _) => BoundSocket::Unix(stage_unix(path)?),
+    })
+}
+
+/// Serve a listening socket handed over by a previous daemon.
+///
+/// The socket keeps the options it was bound with until the address is
+/// bound again.
+pub(crate) fn adopt_listener(
+    fd: OwnedFd,
+    addr: &ListenAddr,
+    protocol: AppProtocol,
+) -> Result<Listener, DaemonError> {
+    Ok(match (addr, protocol) {
+        (ListenAddr::Inet(_), AppProtocol::Tcp) => {
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true)?;
+            Listener::Tcp(TcpListener::from_std(listener)?)
+        }
+        (ListenAddr::Inet(_), AppProtocol::Udp) => {
+            let socket = std::net::UdpSocket::from(fd);
+            socket.set_nonblocking(true)?;
+            Listener::Udp(Arc::new(UdpSocket::from_std(socket)?))
+        }
+        (ListenAddr::Unix(_), _) => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true)?;
+            Listener::Unix(UnixListener::from_std(listener)?)
+        }
+    })
+}
+
+/// Bind a Unix domain socket listener at `path`.
+///
+/// Unix sockets can't be shared like ports, so the new socket is bound to a
+/// temporary path and atomically renamed over `path`. Connections already
+/// queued on the previous socket are still served by the old proxy, while
+/// new connections reach the new socket.
+pub(crate) fn bind_unix_with_replace(path: &Path) -> Result<UnixListener, DaemonError> {
+    Ok(stage_unix(path)?.activate()?)
+}
+
+/// Bind a Unix domain socket listener on a staging path, to be renamed
+/// over `path` once activated.
+fn stage_unix(path: &Path) -> io::Result<StagedUnixListener> {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(format!(".{}.tmp", std::process::id()));
+    let staging = PathBuf::from(staging);
+
+    // A previous daemon may have crashed while staging.
+    let _ = fs::remove_file(&staging);
+    let listener = UnixListener::bind(&staging)?;
+    Ok(StagedUnixListener {
+        listener: Some(listener),
+        staging,
+        path: path.to_owned(),
+    })
+}
+
+/// Retry an operation until it is either successful, or out of attempts.
+///
+/// Waits between attempts without blocking the runtime, so dropping the
+/// returned future cancels the remaining attempts, e.g. once a newer
+/// configuration arrives.
+async fn retry<F, T>(op: F, option: BindSocketRetryOption) -> io::Result<T>
 where
-    F: Fn() -> Result<T, E>,
+    F: Fn() -> io::Result<T>,
 {
-    let mut sleep_duration = Duration::ZERO;
-
+    let mut failures = 0;
     loop {
         match op() {
-            result @ Ok(_) => return result,
-            result @ Err(_) => {
-                option.attempts -= 1;
-                if option.attempts == 0 {
-                    return result;
-                }
-
-                sleep_duration += option.increment_duration;
-                if sleep_duration > option.max_delay_duration {
-                    sleep_duration = option.increment_duration;
+            Ok(value) => return Ok(value),
+            Err(error) => {
+                failures += 1;
+                if failures >= u32::from(option.attempts) {
+                    return Err(error);
                 }
 
-                sleep(sleep_duration);
+                let delay = option.delay(failures);
+                warn!(
+                    attempt = failures,
+                    attempts = option.attempts,
+                    delay_ms = delay.as_millis() as u64;
+                    "failed to bind socket, retrying: {error}"
+                );
+                sleep(delay).await;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::retry;
+    use super::BindSocketRetryOption;
+    use std::cell::Cell;
+    use std::io;
+    use std::io::ErrorKind;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retry_delay_backs_off_with_jitter() {
+        let option = BindSocketRetryOption::builder()
+            .increment_duration(Duration::from_secs(1))
+            .max_delay_duration(Duration::from_secs(5))
+            .build();
+
+        for (failures, backoff) in [(1, 1), (2, 2), (3, 4), (4, 5), (30, 5)] {
+            let delay = option.delay(failures);
+            let backoff = Duration::from_secs(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up_after_attempts() {
+        let option = BindSocketRetryOption::builder()
+            .attempts(3)
+            .increment_duration(Duration::from_millis(1))
+            .build();
+        let calls = Cell::new(0);
+        let result = retry(
+            || -> io::Result<()> {
+                calls.set(calls.get() + 1);
+                Err(ErrorKind::AddrInUse.into())
+            },
+            option,
+        )
+        .await;
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::AddrInUse);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn test_increment_duration_is_the_first_backoff() {
+        let option = BindSocketRetryOption::builder().build();
+        assert_eq!(option.increment_duration, Duration::from_secs(10));
+
+        let delay = option.delay(1);
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10));
+    }
+}
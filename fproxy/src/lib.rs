@@ -3,6 +3,11 @@ mod daemon;
 pub mod dns;
 mod proxy;
 mod strategy;
+mod tls;
 
 pub use self::config::*;
 pub use self::daemon::*;
+pub use self::proxy::splice_bidirectional;
+pub use self::proxy::AcceptErrorCounters;
+pub use self::proxy::AccessCounters;
+pub use self::proxy::ShutdownReport;
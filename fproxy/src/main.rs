@@ -1,24 +1,32 @@
-use std::sync::Arc;
-
 use fproxy::dns::default_async_dns_resolver;
 use fproxy::BindSocketRetryOption;
 use fproxy::ConfigFileSubscriber;
 use fproxy::ConfigSubscriber;
 use fproxy::Daemon;
 use fproxy::DaemonConfig;
+use fproxy::DaemonHandle;
+use log::error;
+use log::info;
 use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
 
+/// Exit statuses:
+/// - `0`: every session drained before shutting down.
+/// - `1`: sessions were closed, or proxies aborted, at the shutdown timeout.
+/// - `2`: the upgrade was aborted, leaving the previous daemon serving.
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     env_logger::init();
 
     let config_path =
         env::var("FPROXY_CONFIG_PATH").expect("missing env variable `FPROXY_CONFIG_PATH`");
     let config_subscriber = ConfigFileSubscriber::new(&config_path)
         .subscribe()
-        .expect(&format!(
-            "failed to subscribe to changes in `{config_path}`"
-        ));
+        .unwrap_or_else(|_| panic!("failed to subscribe to changes in `{config_path}`"));
 
     let dns_resolver = default_async_dns_resolver()
         .await
@@ -27,11 +35,50 @@ async fn main() {
     let daemon_config = DaemonConfig::builder()
         .config_subscriber(config_subscriber)
         .bind_socket_retry_option(BindSocketRetryOption::builder().build())
-        .dns_resolver(Arc::new(dns_resolver))
+        .dns_resolver(dns_resolver)
+        .upgrade_socket(env::var_os("FPROXY_UPGRADE_SOCKET").map(PathBuf::from))
+        .atomic_rollout(env::var_os("FPROXY_ATOMIC_ROLLOUT").is_some())
         .build();
 
-    Daemon::new(daemon_config)
-        .expect("failed to startup daemon")
-        .start()
-        .await;
+    let mut daemon = Daemon::new(daemon_config).expect("failed to startup daemon");
+    let handle = daemon.handle();
+    tokio::spawn(async move {
+        if let Err(error) = handle_signals(handle).await {
+            error!("failed to handle signals: {error}");
+        }
+    });
+
+    match daemon.start().await {
+        Ok(report) if report.is_clean() => ExitCode::SUCCESS,
+        Ok(_) => ExitCode::FAILURE,
+        Err(error) => {
+            error!("failed to upgrade: {error:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Drain every proxy and exit on SIGTERM or SIGINT, and reload the
+/// configuration on SIGHUP.
+async fn handle_signals(handle: DaemonHandle) -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => {
+                info!("received SIGTERM");
+                handle.shutdown();
+            }
+            _ = interrupt.recv() => {
+                info!("received SIGINT");
+                handle.shutdown();
+            }
+            _ = hangup.recv() => {
+                info!("received SIGHUP");
+                handle.reload();
+            }
+        }
+    }
 }
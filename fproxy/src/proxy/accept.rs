@@ -0,0 +1,265 @@
+use crate::proxy::Listener;
+use futures::FutureExt;
+use log::as_serde;
+use log::debug;
+use log::error;
+use log::warn;
+use serde::Serialize;
+use std::fs::File;
+use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Delay after the first accept error caused by exhausted resources,
+/// doubled on every further one in a row.
+const MIN_ACCEPT_DELAY: Duration = Duration::from_millis(5);
+
+/// Longest delay between accepts while resources are exhausted.
+const MAX_ACCEPT_DELAY: Duration = Duration::from_secs(1);
+
+/// How an accept error affects the accept loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AcceptErrorKind {
+    /// The pending connection failed before it was accepted, and the next
+    /// one can be accepted right away.
+    Transient,
+
+    /// The process or system ran out of file descriptors or memory, so
+    /// accepting again right away would fail the same way.
+    Exhausted,
+
+    /// The listening socket can't be served anymore.
+    Fatal,
+}
+
+impl AcceptErrorKind {
+    /// Classify an error returned by `accept(2)`.
+    pub(crate) fn of(error: &io::Error) -> Self {
+        match error.raw_os_error() {
+            Some(
+                libc::ECONNABORTED
+                | libc::ECONNRESET
+                | libc::EPROTO
+                | libc::EPERM
+                | libc::EINTR
+                | libc::EAGAIN
+                | libc::ETIMEDOUT
+                | libc::ENETDOWN
+                | libc::ENETUNREACH
+                | libc::EHOSTDOWN
+                | libc::EHOSTUNREACH
+                | libc::ENOPROTOOPT
+                | libc::EOPNOTSUPP,
+            ) => Self::Transient,
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) => Self::Exhausted,
+            _ => Self::Fatal,
+        }
+    }
+}
+
+/// Accept errors of a proxy's accept loops so far, by kind.
+#[derive(Debug, Default)]
+pub struct AcceptErrorCounters {
+    transient: AtomicU64,
+    exhausted: AtomicU64,
+    shed: AtomicU64,
+}
+
+impl AcceptErrorCounters {
+    /// Pending connections that failed before they were accepted.
+    pub fn transient(&self) -> u64 {
+        self.transient.load(Ordering::Relaxed)
+    }
+
+    /// Accepts that failed because the process or system ran out of file
+    /// descriptors or memory.
+    pub fn exhausted(&self) -> u64 {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    /// Connections accepted and closed right away while out of file
+    /// descriptors.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+/// Accept errors of an accept loop, and the back-off they call for.
+#[derive(Debug, Default)]
+pub(crate) struct AcceptErrors {
+    /// Errors so far, shared with the proxy's other accept loops.
+    counters: Arc<AcceptErrorCounters>,
+    /// Errors caused by exhausted resources in a row.
+    streak: u32,
+    /// Spare file descriptor, closed to make room for a connection to shed.
+    reserve: Option<File>,
+}
+
+impl AcceptErrors {
+    /// Errors of an accept loop, counted in `counters`, keeping a spare file
+    /// descriptor if `reserve_fd` is set.
+    pub(crate) fn new(reserve_fd: bool, counters: Arc<AcceptErrorCounters>) -> Self {
+        let reserve = match reserve_fd {
+            true => open_reserve(),
+            false => None,
+        };
+
+        Self {
+            counters,
+            reserve,
+            ..Default::default()
+        }
+    }
+
+    /// Record a successful accept, ending any back-off.
+    pub(crate) fn accepted(&mut self) {
+        self.streak = 0;
+    }
+
+    /// Record an accept error, returning how long to wait before accepting
+    /// again, or `None` if the accept loop should stop.
+    ///
+    /// Once out of file descriptors, the spare one is closed to accept a
+    /// pending connection and close it, so clients are turned away instead
+    /// of waiting in the backlog. Pending connections are shed one after
+    /// the other, and the accept loop only backs off once none is left.
+    pub(crate) fn record(
+        &mut self,
+        listener: &Listener,
+        index: usize,
+        error: io::Error,
+    ) -> Option<Duration> {
+        let kind = AcceptErrorKind::of(&error);
+        match kind {
+            AcceptErrorKind::Transient => {
+                let errors = self.counters.transient.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!(
+                    listener = index,
+                    kind = as_serde!(kind),
+                    errors = errors;
+                    "failed to accept connection: {error}"
+                );
+                Some(Duration::ZERO)
+            }
+            AcceptErrorKind::Exhausted => {
+                let errors = self.counters.exhausted.fetch_add(1, Ordering::Relaxed) + 1;
+                if self.shed_pending(listener, &error) {
+                    return Some(Duration::ZERO);
+                }
+
+                let delay = MIN_ACCEPT_DELAY
+                    .saturating_mul(2u32.saturating_pow(self.streak))
+                    .min(MAX_ACCEPT_DELAY);
+                self.streak = self.streak.saturating_add(1);
+                warn!(
+                    listener = index,
+                    kind = as_serde!(kind),
+                    errors = errors,
+                    shed = self.counters.shed(),
+                    delay_ms = delay.as_millis() as u64;
+                    "failed to accept connection, backing off: {error}"
+                );
+                Some(delay)
+            }
+            AcceptErrorKind::Fatal => {
+                error!(
+                    listener = index,
+                    kind = as_serde!(kind);
+                    "failed to accept connection, stopping accept loop: {error}"
+                );
+                None
+            }
+        }
+    }
+
+    /// Accept a pending connection and close it right away, using the spare
+    /// file descriptor. Returns `true` if a connection was shed.
+    fn shed_pending(&mut self, listener: &Listener, error: &io::Error) -> bool {
+        if error.raw_os_error() != Some(libc::EMFILE) || self.reserve.take().is_none() {
+            return false;
+        }
+
+        // The listener stays ready after an error, so accepting is attempted
+        // right away instead of waiting for the next connection.
+        let shed = matches!(listener.accept().now_or_never(), Some(Ok(_)));
+        if shed {
+            self.counters.shed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.reserve = open_reserve();
+        shed
+    }
+}
+
+/// Open a spare file descriptor.
+fn open_reserve() -> Option<File> {
+    File::open("/dev/null")
+        .map_err(|error| warn!("failed to open spare file descriptor: {error}"))
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::AcceptErrorKind;
+    use super::AcceptErrors;
+    use crate::proxy::Listener;
+    use std::io;
+    use std::io::Read;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_exhausted_resources_back_off() {
+        let os_error = |code| io::Error::from_raw_os_error(code);
+        assert_eq!(
+            AcceptErrorKind::of(&os_error(libc::ECONNABORTED)),
+            AcceptErrorKind::Transient
+        );
+        assert_eq!(
+            AcceptErrorKind::of(&os_error(libc::EMFILE)),
+            AcceptErrorKind::Exhausted
+        );
+        assert_eq!(
+            AcceptErrorKind::of(&os_error(libc::EBADF)),
+            AcceptErrorKind::Fatal
+        );
+
+        let listener = Listener::Tcp(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let mut errors = AcceptErrors::new(false, Default::default());
+        let mut record = |code| errors.record(&listener, 0, os_error(code));
+        assert_eq!(record(libc::ECONNABORTED), Some(Duration::ZERO));
+        assert_eq!(record(libc::ENFILE), Some(Duration::from_millis(5)));
+        assert_eq!(record(libc::ENFILE), Some(Duration::from_millis(10)));
+        assert_eq!(record(libc::EBADF), None);
+
+        errors.accepted();
+        let delay = errors.record(&listener, 0, os_error(libc::ENFILE));
+        assert_eq!(delay, Some(Duration::from_millis(5)));
+    }
+
+    #[tokio::test]
+    async fn test_pending_connections_are_shed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let listener = Listener::Tcp(listener);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut errors = AcceptErrors::new(true, Default::default());
+        let emfile = || io::Error::from_raw_os_error(libc::EMFILE);
+        assert_eq!(errors.record(&listener, 0, emfile()), Some(Duration::ZERO));
+        assert_eq!(errors.counters.shed(), 1);
+        assert_eq!(errors.counters.exhausted(), 1);
+        assert!(errors.reserve.is_some());
+
+        // The shed connection is closed.
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+
+        // Without pending connections left, the accept loop backs off.
+        let delay = errors.record(&listener, 0, emfile());
+        assert_eq!(delay, Some(Duration::from_millis(5)));
+    }
+}
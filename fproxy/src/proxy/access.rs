@@ -0,0 +1,132 @@
+use crate::proxy::error::Error;
+use crate::proxy::Route;
+use ipnet::IpNet;
+use log::as_serde;
+use log::debug;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Outcome of checking a client against an [`AccessList`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccessDecision {
+    Allowed,
+    Denied,
+}
+
+/// Networks clients of an app may, or may not, connect from.
+///
+/// Clients are allowed if the allow list is empty or has a network they
+/// belong to, and no network of the deny list matches them. IPv4 clients of
+/// dual-stack listeners are matched by their IPv4 address.
+///
+/// The list counts its decisions in `counters`, and every access log line
+/// carries the counts so far.
+#[derive(Debug)]
+pub struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    counters: Arc<AccessCounters>,
+}
+
+/// Access decisions of an app so far.
+#[derive(Debug, Default)]
+pub struct AccessCounters {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+}
+
+impl AccessCounters {
+    /// Clients allowed to connect.
+    pub fn allowed(&self) -> u64 {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    /// Clients denied by the access list.
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>, counters: Arc<AccessCounters>) -> Self {
+        Self {
+            allow,
+            deny,
+            counters,
+        }
+    }
+
+    /// Decide whether the client may connect, and count the decision.
+    pub(crate) fn check(&self, client: IpAddr) -> AccessDecision {
+        let client = client.to_canonical();
+        let allowed = (self.allow.is_empty() || self.allow.iter().any(|n| n.contains(&client)))
+            && !self.deny.iter().any(|n| n.contains(&client));
+
+        if allowed {
+            self.counters.allowed.fetch_add(1, Ordering::Relaxed);
+            AccessDecision::Allowed
+        } else {
+            self.counters.denied.fetch_add(1, Ordering::Relaxed);
+            AccessDecision::Denied
+        }
+    }
+}
+
+/// Check the client against the access list of the app the route belongs
+/// to, recording the decision in the access log at debug level, since it's
+/// written for every connection and datagram of a new client.
+pub(crate) fn check_access(route: &Route, client: IpAddr) -> Result<(), Error> {
+    let Some(access_list) = &route.access_list else {
+        return Ok(());
+    };
+
+    let decision = access_list.check(client);
+    debug!(
+        app = as_serde!(route.app),
+        client = as_serde!(client),
+        decision = as_serde!(decision),
+        allowed = access_list.counters.allowed(),
+        denied = access_list.counters.denied();
+        "access decision"
+    );
+
+    match decision {
+        AccessDecision::Allowed => Ok(()),
+        AccessDecision::Denied => Err(Error::Denied(client)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AccessDecision;
+    use super::AccessList;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_deny_takes_precedence_over_allow() {
+        let net = |net: &str| net.parse().unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let list = AccessList::new(
+            vec![net("10.0.0.0/8"), net("2001:db8::/32")],
+            vec![net("10.1.0.0/16")],
+            Default::default(),
+        );
+
+        assert_eq!(list.check(ip("10.2.3.4")), AccessDecision::Allowed);
+        assert_eq!(list.check(ip("::ffff:10.2.3.4")), AccessDecision::Allowed);
+        assert_eq!(list.check(ip("2001:db8::1")), AccessDecision::Allowed);
+        assert_eq!(list.check(ip("10.1.2.3")), AccessDecision::Denied);
+        assert_eq!(list.check(ip("192.0.2.1")), AccessDecision::Denied);
+        assert_eq!(list.counters.allowed(), 3);
+        assert_eq!(list.counters.denied(), 2);
+
+        // Without an allow list, everything but the deny list is allowed.
+        let list = AccessList::new(vec![], vec![net("10.1.0.0/16")], Default::default());
+        assert_eq!(list.check(ip("192.0.2.1")), AccessDecision::Allowed);
+        assert_eq!(list.check(ip("10.1.2.3")), AccessDecision::Denied);
+    }
+}
@@ -1,15 +1,26 @@
 use super::error::Error;
 use super::ProxyConfig;
+use super::Route;
+use super::TargetStream;
+use crate::config::Port;
+use crate::config::SocketOptions;
 use crate::config::TargetAddr;
+use crate::proxy::socket::configure_connect;
 use futures::stream::FuturesUnordered;
 use futures::TryStreamExt;
 use socket2::Domain;
 use socket2::Protocol;
 use socket2::Socket;
 use socket2::Type;
+use std::io;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+use tokio::net::UnixStream;
 use tokio::time::timeout;
 
 /// A thin client for establishing network connections to
@@ -19,45 +30,47 @@ use tokio::time::timeout;
 /// - Accept a target client config instead of the entire proxy config.
 pub struct TargetClient {
     config: Arc<ProxyConfig>,
+    route: Arc<Route>,
 }
 
 impl TargetClient {
-    pub fn new(config: Arc<ProxyConfig>) -> Self {
-        Self { config }
+    pub fn new(config: Arc<ProxyConfig>, route: Arc<Route>) -> Self {
+        Self { config, route }
     }
 
     /// Attempt to connect to all available target based on the balancing strategy.
     ///
     /// For each target, we perform a DNS lookup to get available IPs, and proceed
     /// to connect to all in chunks of 5, and returns with the first TcpStream
-    /// that got established.
+    /// that got established. If the app connects to targets over TLS, the
+    /// handshake is completed before returning.
     ///
     /// Improvement(s):
     /// - Depending on if an app has v4/v6 enabled, filter addresses to connect to by type instead of
     ///   using all the addresses.
     /// - Allow configuring the chunks
-    pub async fn connect(&self) -> Result<TcpStream, Error> {
+    pub async fn connect(&self) -> Result<TargetStream, Error> {
         timeout(self.config.connection_timeout, self.try_connect()).await?
     }
 
-    async fn try_connect(&self) -> Result<TcpStream, Error> {
-        while let Some(target) = self.config.target_resolver.next() {
-            let addresses = self.lookup(target).await?;
+    async fn try_connect(&self) -> Result<TargetStream, Error> {
+        while let Some(target) = self.route.target_resolver.next() {
+            let (addr, port) = match target {
+                TargetAddr::Inet { addr, port } => (addr, *port),
+                TargetAddr::Unix(path) => {
+                    return Ok(TargetStream::Unix(UnixStream::connect(path).await?))
+                }
+            };
 
+            let addresses = self.lookup(addr, port).await?;
             for addresses in addresses.chunks(self.config.num_parallel_address_connections) {
                 let connect_iter = addresses.iter().copied().map(|address| {
-                    let config = self.config.clone();
-                    async move {
-                        let domain = Domain::for_address(address);
-                        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
-                        socket.set_tcp_keepalive(&config.keep_alive)?;
-                        socket.connect(&(address).into())?;
-                        TcpStream::from_std(socket.into())
-                    }
+                    let route = self.route.clone();
+                    async move { connect_tcp(address, &route.socket_options).await }
                 });
 
                 if let Some(stream) = FuturesUnordered::from_iter(connect_iter).try_next().await? {
-                    return Ok(stream);
+                    return self.secure(addr, stream).await;
                 }
             }
         }
@@ -65,14 +78,85 @@ impl TargetClient {
         Err(Error::InvalidAddr)
     }
 
-    async fn lookup(&self, target: &TargetAddr) -> Result<Vec<SocketAddr>, Error> {
+    /// Open a UDP socket connected to a target based on the balancing strategy.
+    ///
+    /// UDP is connectionless, so the first address a target resolves to is used.
+    pub async fn connect_udp(&self) -> Result<UdpSocket, Error> {
+        timeout(self.config.connection_timeout, self.try_connect_udp()).await?
+    }
+
+    async fn try_connect_udp(&self) -> Result<UdpSocket, Error> {
+        while let Some(target) = self.route.target_resolver.next() {
+            let TargetAddr::Inet { addr, port } = target else {
+                return Err(Error::InvalidAddr);
+            };
+
+            let Some(address) = self.lookup(addr, *port).await?.first().copied() else {
+                continue;
+            };
+
+            let local = match address {
+                SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            };
+
+            let socket = UdpSocket::bind(local).await?;
+            socket.connect(address).await?;
+            return Ok(socket);
+        }
+
+        Err(Error::InvalidAddr)
+    }
+
+    /// Originate TLS on an established stream if the app requires it.
+    async fn secure(&self, addr: &str, stream: TcpStream) -> Result<TargetStream, Error> {
+        match &self.route.upstream_tls {
+            None => Ok(TargetStream::Tcp(stream)),
+            Some(tls) => {
+                let stream = tls.connect(addr, stream).await?;
+                Ok(TargetStream::Tls(Box::new(stream)))
+            }
+        }
+    }
+
+    async fn lookup(&self, addr: &str, port: Port) -> Result<Vec<SocketAddr>, Error> {
+        if let Ok(ip) = addr.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
         Ok(self
             .config
             .dns_resolver
-            .lookup_ip(&format!("{0}.", target.addr))
+            .lookup_ip(&format!("{0}.", addr))
             .await?
             .iter()
-            .map(|ip| SocketAddr::new(ip, target.port))
+            .map(|ip| SocketAddr::new(ip, port))
             .collect())
     }
 }
+
+/// Open a TCP connection to `address` without blocking the runtime.
+///
+/// The socket is non-blocking from the start, as forwarding (e.g. with
+/// `splice(2)`) relies on it.
+async fn connect_tcp(address: SocketAddr, options: &SocketOptions) -> io::Result<TcpStream> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    configure_connect(&socket, options)?;
+    socket.set_nonblocking(true)?;
+    match socket.connect(&address.into()) {
+        Ok(()) => {}
+        Err(error) if error.raw_os_error() == Some(libc::EINPROGRESS) => {}
+        Err(error) => return Err(error),
+    }
+
+    let stream = TcpStream::from_std(socket.into())?;
+    stream.writable().await?;
+    match stream.take_error()? {
+        Some(error) => Err(error),
+        None => Ok(stream),
+    }
+}
@@ -1,39 +1,138 @@
-use crate::config::TargetAddr;
-use crate::strategy::Strategy;
-use socket2::TcpKeepalive;
+use crate::config::LimitPolicy;
+use crate::config::Protocol;
+use crate::config::SessionLimitPolicy;
+use crate::proxy::AcceptErrorCounters;
+use crate::proxy::ClientStream;
+use crate::proxy::Router;
+use serde::Serialize;
 use std::fmt::Debug;
+use std::io;
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::os::fd::OwnedFd;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::net::UdpSocket;
+use tokio::net::UnixListener;
+use tokio::sync::Semaphore;
 use trust_dns_resolver::TokioAsyncResolver;
 use typed_builder::TypedBuilder;
 
+/// Socket a proxy receives client traffic on.
+#[derive(Debug)]
+pub enum Listener {
+    /// Accepts TCP connections.
+    Tcp(TcpListener),
+
+    /// Receives UDP datagrams.
+    Udp(Arc<UdpSocket>),
+
+    /// Accepts Unix domain socket connections.
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Transport protocol of the listening socket.
+    ///
+    /// Unix domain sockets are stream based, so they count as TCP.
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            Self::Tcp(_) | Self::Unix(_) => Protocol::Tcp,
+            Self::Udp(_) => Protocol::Udp,
+        }
+    }
+
+    /// Duplicate the listening socket, e.g. to hand it over to another
+    /// process.
+    pub(crate) fn try_clone_fd(&self) -> io::Result<OwnedFd> {
+        let fd = match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Udp(socket) => socket.as_raw_fd(),
+            Self::Unix(listener) => listener.as_raw_fd(),
+        };
+
+        // SAFETY: the file descriptor is owned by the listener, which
+        // outlives the borrow.
+        unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()
+    }
+
+    /// Accept the next connection on a stream based listener.
+    pub(crate) async fn accept(&self) -> io::Result<ClientStream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().await.map(|(s, _)| ClientStream::Tcp(s)),
+            Self::Unix(listener) => listener.accept().await.map(|(s, _)| ClientStream::Unix(s)),
+            Self::Udp(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "udp sockets don't accept connections",
+            )),
+        }
+    }
+}
+
+/// Configuration rollout a proxy was started by.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Generation {
+    /// Rollouts started by the daemon so far, including this one.
+    pub number: u64,
+
+    /// Version of the configuration, if it has one.
+    pub version: Option<String>,
+}
+
 #[derive(TypedBuilder)]
 pub struct ProxyConfig {
     /// DNS resolver
-    pub dns_resolver: Arc<&'static TokioAsyncResolver>,
+    pub dns_resolver: &'static TokioAsyncResolver,
+
+    /// Underlying listening sockets, each served by its own accept loop.
+    ///
+    /// Multiple sockets bound to the same address with port re-use let the
+    /// kernel spread connections across the accept loops.
+    ///
+    /// The proxy takes the sockets over once listening, so they are closed
+    /// as soon as it stops accepting, rather than once its last session ends.
+    pub listeners: Vec<Listener>,
+
+    /// Run each accept loop, and the connections it accepts, on a dedicated
+    /// single threaded runtime instead of the shared one.
+    #[builder(default = false)]
+    pub per_core_runtime: bool,
+
+    /// Decides what app (and therefore what targets) a connection goes to.
+    pub router: Router,
 
-    /// Underlying TCP listener.
-    pub listener: TcpListener,
+    /// Configuration rollout the proxy was started by. For listeners shared
+    /// between apps, that's the rollout that bound them.
+    #[builder(default)]
+    pub generation: Generation,
 
-    /// Strategy for resolving what target to connect to.
-    pub target_resolver: Arc<dyn Strategy<Item = TargetAddr>>,
+    /// Limits every accepted connection counts against.
+    #[builder(default)]
+    pub connection_limits: Vec<Arc<Semaphore>>,
 
-    /// Max time to wait for graceful shutdown.
+    /// What happens to new connections once a limit is reached.
+    #[builder(default)]
+    pub connection_limit_policy: LimitPolicy,
+
+    /// Keep a spare file descriptor per accept loop, closed to accept and
+    /// shed pending connections once the process runs out of them.
+    #[builder(default = false)]
+    pub accept_reserve_fd: bool,
+
+    /// Counters of the accept errors of every accept loop, e.g. shared with
+    /// the proxies previously serving the same listener.
+    #[builder(default)]
+    pub accept_errors: Arc<AcceptErrorCounters>,
+
+    /// Max time to wait for graceful shutdown. Sessions still open once it
+    /// expires are closed.
     ///
     /// Default value: 10 seconds
     #[builder(default = Duration::from_millis(10000))]
     pub shutdown_timeout: Duration,
 
-    /// Delay duration before retrying shutdown after previous attempt failed.
-    ///
-    /// This should always be less than the `shutdown_timeout` which is the max
-    /// time to wait.
-    ///
-    /// Default value: 100 milliseconds
-    #[builder(default = Duration::from_millis(100))]
-    pub shutdown_retry_delay: Duration,
-
     /// The maximum time to wait for a network connection to be
     /// established with target, in milliseconds.
     ///
@@ -41,19 +140,54 @@ pub struct ProxyConfig {
     #[builder(default = Duration::from_millis(30000))]
     pub connection_timeout: Duration,
 
+    /// Max time to wait for a client to send its TLS ClientHello when
+    /// the listener routes by server name.
+    ///
+    /// Default value: 5 seconds
+    #[builder(default = Duration::from_secs(5))]
+    pub client_hello_timeout: Duration,
+
+    /// Max number of bytes to peek while looking for the TLS ClientHello.
+    ///
+    /// Default value: 16 KiB
+    #[builder(default = 16 * 1024)]
+    pub max_client_hello_size: usize,
+
+    /// Max time to wait for the TLS handshake to complete when the
+    /// app terminates TLS.
+    ///
+    /// Default value: 10 seconds
+    #[builder(default = Duration::from_secs(10))]
+    pub tls_handshake_timeout: Duration,
+
+    /// Max time a UDP session may go without a datagram in either direction
+    /// before its upstream socket is closed.
+    ///
+    /// Default value: 60 seconds
+    #[builder(default = Duration::from_secs(60))]
+    pub udp_session_idle_timeout: Duration,
+
+    /// Max UDP sessions open at once on the listener.
+    ///
+    /// Default value: 65536
+    #[builder(default = 65536)]
+    pub max_udp_sessions: usize,
+
+    /// What happens to datagrams of new clients once `max_udp_sessions` is
+    /// reached.
+    #[builder(default)]
+    pub udp_session_limit_policy: SessionLimitPolicy,
+
+    /// Largest UDP datagram relayed in either direction.
+    ///
+    /// Default value: 65535 bytes
+    #[builder(default = 65535)]
+    pub max_datagram_size: usize,
+
     /// Buffer size for the signal channel.
     #[builder(default = 5)]
     pub signal_buffer_size: usize,
 
-    /// Keep alive configuration for the proxy
-    #[builder(
-        default = TcpKeepalive::new()
-            .with_interval(Duration::from_secs(75))
-            .with_time(Duration::from_secs(10))
-            .with_retries(9)
-    )]
-    pub keep_alive: TcpKeepalive,
-
     /// The number of lookup addresses to attempt connecting to in parallel.
     #[builder(default = 5)]
     pub num_parallel_address_connections: usize,
@@ -62,12 +196,24 @@ pub struct ProxyConfig {
 impl Debug for ProxyConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ProxyConfig")
-            .field("listener", &self.listener)
+            .field("listeners", &self.listeners)
+            .field("per_core_runtime", &self.per_core_runtime)
+            .field("router", &self.router)
+            .field("generation", &self.generation)
+            .field("connection_limits", &self.connection_limits)
+            .field("connection_limit_policy", &self.connection_limit_policy)
+            .field("accept_reserve_fd", &self.accept_reserve_fd)
+            .field("accept_errors", &self.accept_errors)
             .field("shutdown_timeout", &self.shutdown_timeout)
-            .field("shutdown_retry_delay", &self.shutdown_retry_delay)
             .field("signal_buffer_size", &self.signal_buffer_size)
             .field("connection_timeout", &self.connection_timeout)
-            .field("keep_alive", &self.keep_alive)
+            .field("client_hello_timeout", &self.client_hello_timeout)
+            .field("max_client_hello_size", &self.max_client_hello_size)
+            .field("tls_handshake_timeout", &self.tls_handshake_timeout)
+            .field("udp_session_idle_timeout", &self.udp_session_idle_timeout)
+            .field("max_udp_sessions", &self.max_udp_sessions)
+            .field("udp_session_limit_policy", &self.udp_session_limit_policy)
+            .field("max_datagram_size", &self.max_datagram_size)
             .field(
                 "num_parallel_address_connections",
                 &self.num_parallel_address_connections,
@@ -1,20 +1,55 @@
+use super::sni::ParseClientHelloError;
+use std::net::IpAddr;
+use thiserror::Error;
 use tokio::time::error::Elapsed;
 use trust_dns_resolver::error::ResolveError;
 
-#[derive(Debug)]
+#[derive(Error, Debug)]
 pub enum Error {
     /// IO error.
+    #[error("io error: {0}")]
     Io(std::io::Error),
 
     /// Failed to lookup DNS record for IP address.
+    #[error("dns lookup failed: {0}")]
     DnsLookup(ResolveError),
 
     /// Socket address is invalid or couldn't be resolved.
+    #[error("invalid or unresolvable target address")]
     InvalidAddr,
 
     /// Failed to establish connection to the target before the
     /// conection timeout exceeded.
+    #[error("connection to target timed out")]
     ConnectionTimeout,
+
+    /// No route matched the TLS server name sent by the client.
+    #[error("no route for server name {0:?}")]
+    NoRoute(Option<String>),
+
+    /// TLS handshake with the client didn't complete before the timeout elapsed.
+    #[error("tls handshake timed out")]
+    TlsHandshakeTimeout,
+
+    /// The app the connection was routed to has reached its connection limit.
+    #[error("connection limit of app {0} reached")]
+    ConnectionLimit(String),
+
+    /// The listener has reached its UDP session limit.
+    #[error("udp session limit reached")]
+    SessionLimit,
+
+    /// The client address is denied by the app's access list.
+    #[error("client {0} is not allowed")]
+    Denied(IpAddr),
+
+    /// The client opened connections faster than the app's client rate limit.
+    #[error("connection rate limit of client {0} reached")]
+    RateLimited(IpAddr),
+
+    /// Client didn't send a valid TLS ClientHello before the timeout elapsed.
+    #[error("failed to read client hello: {0}")]
+    ClientHello(ParseClientHelloError),
 }
 
 impl From<std::io::Error> for Error {
@@ -34,3 +69,9 @@ impl From<Elapsed> for Error {
         Self::ConnectionTimeout
     }
 }
+
+impl From<ParseClientHelloError> for Error {
+    fn from(e: ParseClientHelloError) -> Self {
+        Self::ClientHello(e)
+    }
+}
@@ -1,25 +1,111 @@
+mod accept;
+mod access;
 mod client;
 mod config;
 pub mod error;
+mod rate;
+mod route;
+mod session;
+mod sni;
+mod socket;
+mod splice;
+mod stream;
+mod udp;
 
+pub use self::accept::AcceptErrorCounters;
+pub use self::access::AccessCounters;
+pub use self::access::AccessList;
 pub use self::config::*;
+pub use self::rate::BandwidthLimiter;
+pub use self::rate::ClientRateLimiter;
+pub use self::rate::SharedBandwidth;
+pub use self::route::*;
+pub use self::session::CloseReason;
+pub(crate) use self::socket::listen_with_options;
+pub use self::splice::splice_bidirectional;
+pub use self::stream::*;
+use crate::config::LimitPolicy;
+use crate::config::Protocol;
+use crate::proxy::accept::AcceptErrors;
+use crate::proxy::access::check_access;
 use crate::proxy::client::TargetClient;
+use crate::proxy::error::Error;
+use crate::proxy::session::expire_after;
+use crate::proxy::session::forward_session;
+use crate::proxy::session::idle_for;
+use crate::proxy::session::Activity;
+use crate::proxy::sni::peek_server_name;
+use crate::proxy::socket::configure_stream;
+use crate::proxy::udp::handle_datagrams;
 
+use futures::FutureExt;
 use log::as_serde;
 use log::debug;
 use log::error;
+use log::info;
+use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::ops::AddAssign;
 use std::sync::Arc;
-use std::thread::sleep;
-use std::time::Instant;
-use tokio::io::copy_bidirectional;
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::runtime::Builder;
 use tokio::spawn;
-use tokio::sync::mpsc::channel;
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::broadcast::channel;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::Sender;
+use tokio::sync::oneshot;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tokio::task::unconstrained;
+use tokio::task::JoinError;
 use tokio::task::JoinHandle;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+use tokio::time::timeout;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
 use tracing::instrument;
 
+/// Time given to listeners to close the sessions left after draining.
+const FORCE_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Outcome of shutting down a proxy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ShutdownReport {
+    /// Sessions that finished on their own while draining.
+    pub drained: usize,
+
+    /// Sessions closed once the shutdown timeout expired.
+    pub closed: usize,
+
+    /// Accept loops that didn't stop in time, and were aborted along with
+    /// their sessions. Proxies still draining when the daemon's shutdown
+    /// timeout expires count as one.
+    pub aborted: usize,
+}
+
+impl ShutdownReport {
+    /// Returns `true` if every session finished on its own.
+    pub fn is_clean(&self) -> bool {
+        self.closed == 0 && self.aborted == 0
+    }
+}
+
+impl AddAssign for ShutdownReport {
+    fn add_assign(&mut self, other: Self) {
+        self.drained += other.drained;
+        self.closed += other.closed;
+        self.aborted += other.aborted;
+    }
+}
+
 /// Signal type supported by proxy.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy)]
 pub enum Signal {
     /// Shutdown proxy gracefully.
     SIGTERM,
@@ -32,77 +118,1043 @@ pub enum Signal {
 #[derive(Debug)]
 pub struct Proxy {
     tx: Sender<Signal>,
-    request_handler: JoinHandle<()>,
+    acceptors: Vec<Acceptor>,
+    /// Listening sockets, owned apart from the configuration sessions hold,
+    /// so each closes as soon as the proxy and its accept loop let go of it.
+    listeners: Vec<Arc<Listener>>,
     config: Arc<ProxyConfig>,
 }
 
+/// Accept loop serving one of the proxy's listeners.
+#[derive(Debug)]
+struct Acceptor {
+    handle: JoinHandle<AcceptorExit>,
+    /// When the accept loop was last started.
+    started: Instant,
+    /// When the accept loop was first seen stopped, if it stopped.
+    stopped: Option<Instant>,
+    /// Times the accept loop was restarted since it last served for a while.
+    restarts: u32,
+}
+
+/// How an accept loop stopped.
+#[derive(Debug)]
+enum AcceptorExit {
+    /// The proxy was shut down, and the loop's sessions drained.
+    Shutdown(ShutdownReport),
+
+    /// The listener can't be served anymore. Sessions are left open, for
+    /// the restarted loop to take over.
+    Failed(JoinSet<()>),
+}
+
+/// Accept loop that stopped while its proxy is still serving, e.g. because
+/// it panicked.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FailedAcceptor {
+    /// Listener the accept loop served.
+    pub listener: usize,
+    /// Time the accept loop served for before it stopped.
+    pub uptime: Duration,
+    /// Time since the accept loop stopped.
+    pub downtime: Duration,
+    /// Times the accept loop was restarted since it last served for a while.
+    pub restarts: u32,
+}
+
 impl Proxy {
-    /// Start proxying request from the provided TCP listener.
-    pub fn listen(config: ProxyConfig) -> Self {
+    /// Start proxying request from the provided listeners, with one accept
+    /// loop per listener.
+    pub fn listen(mut config: ProxyConfig) -> Self {
+        let listeners = std::mem::take(&mut config.listeners)
+            .into_iter()
+            .map(Arc::new)
+            .collect::<Vec<_>>();
         let config = Arc::new(config);
-        let (tx, rx) = channel::<Signal>(config.signal_buffer_size);
-        let request_handler = spawn(Self::handle_requests(config.clone(), rx));
+        let (tx, _) = channel::<Signal>(config.signal_buffer_size);
+        let acceptors = listeners
+            .iter()
+            .enumerate()
+            .map(|(index, listener)| Acceptor {
+                handle: Self::spawn_acceptor(&config, listener, index, &tx, JoinSet::new()),
+                started: Instant::now(),
+                stopped: None,
+                restarts: 0,
+            })
+            .collect();
 
         Self {
             tx,
             config,
-            request_handler,
+            listeners,
+            acceptors,
+        }
+    }
+
+    /// Spawn the accept loop of a listener, taking over `sessions` left open
+    /// by a previous loop.
+    ///
+    /// Sessions of a loop on a dedicated runtime end along with the runtime,
+    /// so there are none to take over.
+    fn spawn_acceptor(
+        config: &Arc<ProxyConfig>,
+        listener: &Arc<Listener>,
+        index: usize,
+        tx: &Sender<Signal>,
+        sessions: JoinSet<()>,
+    ) -> JoinHandle<AcceptorExit> {
+        if !config.per_core_runtime {
+            return spawn(Self::serve(
+                config.clone(),
+                listener.clone(),
+                index,
+                tx.subscribe(),
+                sessions,
+            ));
         }
+
+        let handler = Self::serve(
+            config.clone(),
+            listener.clone(),
+            index,
+            tx.subscribe(),
+            JoinSet::new(),
+        );
+        spawn_on_dedicated_runtime(index, handler).unwrap_or_else(|error| {
+            error!("failed to start acceptor runtime, using shared runtime: {error}");
+            spawn(Self::serve(
+                config.clone(),
+                listener.clone(),
+                index,
+                tx.subscribe(),
+                JoinSet::new(),
+            ))
+        })
     }
 
-    /// Process an incoming TCP stream.
-    #[instrument(skip(signal_rx, config))]
-    async fn handle_requests(config: Arc<ProxyConfig>, mut signal_rx: Receiver<Signal>) {
+    /// Accept loops that stopped although the proxy wasn't shut down.
+    pub(crate) fn failed_acceptors(&mut self) -> Vec<FailedAcceptor> {
+        let now = Instant::now();
+        self.acceptors
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, acceptor)| acceptor.handle.is_finished())
+            .map(|(listener, acceptor)| {
+                let stopped = *acceptor.stopped.get_or_insert(now);
+                FailedAcceptor {
+                    listener,
+                    uptime: stopped.duration_since(acceptor.started),
+                    downtime: now.duration_since(stopped),
+                    restarts: acceptor.restarts,
+                }
+            })
+            .collect()
+    }
+
+    /// Stop every accept loop, as if it failed.
+    #[cfg(test)]
+    pub(crate) fn abort_acceptors(&self) {
+        self.acceptors
+            .iter()
+            .for_each(|acceptor| acceptor.handle.abort());
+    }
+
+    /// Restart a failed accept loop on the same listener and route, counting
+    /// `restarts` restarts so far. The restarted loop takes over the sessions
+    /// the failed one left open.
+    ///
+    /// Returns the outcome of the failed loop, or `None`, leaving it as is,
+    /// if it's still running.
+    pub(crate) fn restart_acceptor(
+        &mut self,
+        listener: usize,
+        restarts: u32,
+    ) -> Option<Result<(), JoinError>> {
+        // Outside of the cooperative budget, a finished loop always reports
+        // its outcome right away.
+        let outcome = unconstrained(&mut self.acceptors[listener].handle).now_or_never()?;
+        let (sessions, outcome) = match outcome {
+            Ok(AcceptorExit::Failed(sessions)) => (sessions, Ok(())),
+            Ok(AcceptorExit::Shutdown(_)) => (JoinSet::new(), Ok(())),
+            Err(error) => (JoinSet::new(), Err(error)),
+        };
+
+        self.acceptors[listener] = Acceptor {
+            handle: Self::spawn_acceptor(
+                &self.config,
+                &self.listeners[listener],
+                listener,
+                &self.tx,
+                sessions,
+            ),
+            started: Instant::now(),
+            stopped: None,
+            restarts,
+        };
+
+        Some(outcome)
+    }
+
+    /// Serve a listener until the proxy is shut down, or the listener fails.
+    async fn serve(
+        config: Arc<ProxyConfig>,
+        listener: Arc<Listener>,
+        index: usize,
+        signal_rx: Receiver<Signal>,
+        sessions: JoinSet<()>,
+    ) -> AcceptorExit {
+        match *listener {
+            Listener::Tcp(_) | Listener::Unix(_) => {
+                Self::handle_requests(config, listener, index, signal_rx, sessions).await
+            }
+            Listener::Udp(_) => {
+                AcceptorExit::Shutdown(handle_datagrams(config, listener, signal_rx).await)
+            }
+        }
+    }
+
+    /// Process an incoming stream.
+    ///
+    /// Every connection holds a permit of each of the proxy's connection
+    /// limits while open. Once a limit is reached, new connections are either
+    /// closed right after being accepted, or left in the listener's backlog
+    /// until a permit frees up.
+    ///
+    /// Connections from clients denied by the app's access list, or above its
+    /// rate limit, are closed right away, before a target is resolved. On
+    /// listeners shared by server name, the app is only known once the
+    /// connection is routed.
+    ///
+    /// Accept errors for a single pending connection are skipped, while
+    /// running out of file descriptors or memory backs off until resources
+    /// free up. The accept loop stops, and is restarted by the daemon, if
+    /// the listener can't be served anymore. Open sessions are left as is
+    /// then, for the restarted loop to take over.
+    ///
+    /// Once shut down, connections already queued on the listener are
+    /// accepted, and the listener is closed right away, so new connections
+    /// go to the other sockets bound to the address, e.g. the next
+    /// generation's. Open sessions are then given until the shutdown timeout
+    /// to finish, before the remaining ones are closed.
+    #[instrument(skip(signal_rx, config, listener))]
+    async fn handle_requests(
+        config: Arc<ProxyConfig>,
+        listener: Arc<Listener>,
+        index: usize,
+        mut signal_rx: Receiver<Signal>,
+        mut sessions: JoinSet<()>,
+    ) -> AcceptorExit {
+        let policy = config.connection_limit_policy;
+        let mut errors = AcceptErrors::new(config.accept_reserve_fd, config.accept_errors.clone());
+
         loop {
             let config = config.clone();
+            let reserved = match policy {
+                LimitPolicy::Reject => None,
+                LimitPolicy::Pause => tokio::select! {
+                    Ok(Signal::SIGTERM) = signal_rx.recv() => break,
+                    permits = acquire_permits(&config.connection_limits) => Some(permits),
+                },
+            };
 
             tokio::select! {
-                Some(Signal::SIGTERM) = signal_rx.recv() => break,
-                Ok((mut incoming, _)) = config.listener.accept() => {
-                    spawn(async move {
-                        let Ok(mut target) = TargetClient::new(config).connect().await else {
-                            return;
-                        };
-
-                        let address = target.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
-                        if let Err(error) = copy_bidirectional(&mut incoming, &mut target).await {
-                            debug!(destination = as_serde!(address); "write to target failed: {}", error);
-                        }
-                    });
+                Ok(Signal::SIGTERM) = signal_rx.recv() => break,
+                Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+                accepted = listener.accept() => {
+                    let incoming = match accepted {
+                        Ok(incoming) => incoming,
+                        Err(error) => match errors.record(&listener, index, error) {
+                            Some(Duration::ZERO) => continue,
+                            Some(delay) => tokio::select! {
+                                Ok(Signal::SIGTERM) = signal_rx.recv() => break,
+                                _ = sleep(delay) => continue,
+                            },
+                            None => return AcceptorExit::Failed(std::mem::take(&mut sessions)),
+                        },
+                    };
+
+                    errors.accepted();
+                    Self::spawn_session(config, incoming, reserved, &mut sessions);
                 }
             }
         }
+
+        // Connections queued on the listener are reset once it's closed.
+        while let Some(Ok(incoming)) = listener.accept().now_or_never() {
+            Self::spawn_session(config.clone(), incoming, None, &mut sessions);
+        }
+        drop(listener);
+
+        AcceptorExit::Shutdown(Self::drain(&config, index, sessions).await)
     }
 
-    /// Shutdown proxy synchronously.
-    pub fn shutdown(&self) {
-        if let Err(error) = self.tx.try_send(Signal::SIGTERM) {
-            error!("failed to send shutdown signal: {error}");
-            self.request_handler.abort();
-            return;
+    /// Proxy an accepted connection, unless its client or a connection limit
+    /// turns it away. `reserved` holds permits already acquired for it.
+    fn spawn_session(
+        config: Arc<ProxyConfig>,
+        incoming: ClientStream,
+        reserved: Option<Vec<OwnedSemaphorePermit>>,
+        sessions: &mut JoinSet<()>,
+    ) {
+        if let Router::App(route) = &config.router {
+            if let Err(error) = admit(route, &incoming) {
+                debug!(app = as_serde!(route.app); "rejecting connection: {}", error);
+                return;
+            }
         }
 
-        let begin = Instant::now();
-        let Some(end) = begin.checked_add(self.config.shutdown_timeout) else {
-            error!("shutdown timer exceeded possible range");
-            self.request_handler.abort();
+        let Some(permits) = reserved.or_else(|| try_acquire_permits(&config.connection_limits))
+        else {
+            debug!("connection limit reached, rejecting connection");
             return;
         };
 
-        while begin < end {
-            if self.request_handler.is_finished() {
-                return;
+        sessions.spawn(async move {
+            let _permits = permits;
+            if let Err(error) = Self::handle_connection(config, incoming).await {
+                debug!("failed to proxy connection: {}", error);
             }
+        });
+    }
+
+    /// Wait for open sessions to finish until the shutdown timeout, and
+    /// close the remaining ones.
+    async fn drain(
+        config: &ProxyConfig,
+        listener: usize,
+        mut sessions: JoinSet<()>,
+    ) -> ShutdownReport {
+        let open = sessions.len();
+        let finished = async { while sessions.join_next().await.is_some() {} };
+        let _ = timeout(config.shutdown_timeout, finished).await;
+
+        let closed = sessions.len();
+        sessions.shutdown().await;
+        info!(
+            listener = listener,
+            generation = config.generation.number,
+            drained = open - closed,
+            closed = closed;
+            "listener drained"
+        );
+
+        ShutdownReport {
+            drained: open - closed,
+            closed,
+            aborted: 0,
+        }
+    }
+
+    /// Route an accepted connection, terminate TLS if the app requires it,
+    /// and forward it to a target.
+    async fn handle_connection(
+        config: Arc<ProxyConfig>,
+        incoming: ClientStream,
+    ) -> Result<(), Error> {
+        let route = Self::route(&config, &incoming).await?;
+        if let Router::ServerName(_) = &config.router {
+            admit(&route, &incoming)?;
+        }
+
+        let _permit = match &route.connection_limit {
+            Some(limit) => Some(
+                limit
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| Error::ConnectionLimit(route.app.clone()))?,
+            ),
+            None => None,
+        };
+
+        if let ClientStream::Tcp(stream) = &incoming {
+            configure_stream(stream, &route.socket_options)?;
+        }
+
+        let Some(tls) = route.tls_terminator.clone() else {
+            return Self::forward(config, route, incoming).await;
+        };
+
+        let incoming = timeout(
+            config.tls_handshake_timeout,
+            tls.acceptor().accept(incoming),
+        )
+        .await
+        .map_err(|_| Error::TlsHandshakeTimeout)??;
+
+        Self::forward(config, route, incoming).await
+    }
+
+    /// Connect to a target of the route and copy bytes in both directions
+    /// until either side closes.
+    ///
+    /// Plain TCP sessions of apps with splicing enabled are forwarded with
+    /// `splice(2)` instead of being copied through userspace. The session is
+    /// cut short once it has been idle, or open, for longer than the app
+    /// allows.
+    ///
+    /// Sessions ending with an error (e.g. a reset from either side) are
+    /// closed with a reset on both legs, so a peer never mistakes a broken
+    /// session for a complete one. Apps may opt into the same abortive close
+    /// when the proxy cuts a session short.
+    async fn forward<S>(
+        config: Arc<ProxyConfig>,
+        route: Arc<Route>,
+        mut incoming: S,
+    ) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + AsTcpStream + Unpin,
+    {
+        let client = TargetClient::new(config, route.clone());
+        let mut target = client.connect().await?;
+
+        let address = target.peer();
+        let activity = Activity::new();
+        let reason = tokio::select! {
+            result = forward_session(&mut incoming, &mut target, &route, &activity) => match result {
+                Ok(reason) => reason,
+                Err(error) => {
+                    debug!(destination = as_serde!(address); "write to target failed: {}", error);
+                    CloseReason::Error
+                }
+            },
+            _ = idle_for(&activity, route.idle_timeout) => CloseReason::IdleTimeout,
+            _ = expire_after(route.max_connection_duration) => CloseReason::MaxConnectionDuration,
+        };
+
+        let abortive = match reason {
+            CloseReason::Completed => false,
+            CloseReason::Error => true,
+            _ => route.abortive_close,
+        };
+
+        if abortive {
+            for socket in [incoming.tcp_socket(), target.tcp_socket()] {
+                if let Some(Err(error)) =
+                    socket.map(|socket| socket.set_linger(Some(Duration::ZERO)))
+                {
+                    debug!("failed to reset connection: {}", error);
+                }
+            }
+        }
+
+        debug!(
+            app = as_serde!(route.app),
+            destination = as_serde!(address),
+            reason = as_serde!(reason);
+            "connection closed"
+        );
+
+        Ok(())
+    }
+
+    /// Configuration rollout the proxy was started by.
+    pub fn generation(&self) -> &Generation {
+        &self.config.generation
+    }
+
+    /// Sockets the proxy listens on.
+    pub(crate) fn listeners(&self) -> &[Arc<Listener>] {
+        &self.listeners
+    }
+
+    /// Transport protocol the proxy listens on.
+    pub fn protocol(&self) -> Protocol {
+        self.listeners
+            .first()
+            .map_or(Protocol::Tcp, |listener| listener.protocol())
+    }
+
+    /// Resolve the route an accepted connection should take.
+    async fn route(config: &ProxyConfig, incoming: &ClientStream) -> Result<Arc<Route>, Error> {
+        match (&config.router, incoming) {
+            (Router::App(route), _) => Ok(route.clone()),
+            (Router::ServerName(_), ClientStream::Unix(_)) => Err(Error::NoRoute(None)),
+            (Router::ServerName(router), ClientStream::Tcp(incoming)) => {
+                let server_name = peek_server_name(
+                    incoming,
+                    config.max_client_hello_size,
+                    config.client_hello_timeout,
+                )
+                .await?;
+
+                server_name
+                    .as_deref()
+                    .and_then(|name| router.resolve(name))
+                    .ok_or(Error::NoRoute(server_name))
+            }
+        }
+    }
+
+    /// Stop accepting, and wait for open sessions to drain.
+    ///
+    /// Sessions still open once the shutdown timeout expires are closed.
+    /// Accept loops that don't stop shortly after are aborted.
+    pub async fn shutdown(mut self) -> ShutdownReport {
+        // Fails only if every accept loop already stopped.
+        let _ = self.tx.send(Signal::SIGTERM);
+
+        // Accept loops close their listener once they stop accepting.
+        self.listeners.clear();
+
+        // Listeners close the sessions left once the shutdown timeout
+        // expires, so they are given a bit longer to do so.
+        let deadline = self
+            .config
+            .shutdown_timeout
+            .saturating_add(FORCE_CLOSE_TIMEOUT);
+        let deadline = Instant::now().checked_add(deadline);
+        let mut report = ShutdownReport::default();
+        for (index, Acceptor { mut handle, .. }) in
+            std::mem::take(&mut self.acceptors).into_iter().enumerate()
+        {
+            let result = match deadline {
+                Some(deadline) => timeout_at(deadline, &mut handle).await,
+                None => Ok((&mut handle).await),
+            };
 
-            sleep(self.config.shutdown_retry_delay);
+            match result {
+                Ok(Ok(AcceptorExit::Shutdown(listener))) => report += listener,
+                // Failed loops not restarted yet left their sessions open.
+                Ok(Ok(AcceptorExit::Failed(sessions))) => {
+                    report += Self::drain(&self.config, index, sessions).await
+                }
+                Ok(Err(error)) => {
+                    error!("accept loop failed: {error}");
+                    report.aborted += 1;
+                }
+                Err(_) => {
+                    handle.abort();
+                    report.aborted += 1;
+                }
+            }
         }
 
-        self.request_handler.abort();
+        report
+    }
+}
+
+/// Check the client against the access list and rate limit of the app the
+/// route belongs to.
+fn admit(route: &Route, incoming: &ClientStream) -> Result<(), Error> {
+    match incoming.peer_ip() {
+        Some(client) => admit_client(route, client),
+        None => Ok(()),
+    }
+}
+
+/// Check a client address against the access list and rate limit of the app
+/// the route belongs to.
+pub(crate) fn admit_client(route: &Route, client: IpAddr) -> Result<(), Error> {
+    check_access(route, client)?;
+    match &route.client_rate_limit {
+        Some(limiter) if !limiter.try_acquire(client) => Err(Error::RateLimited(client)),
+        _ => Ok(()),
+    }
+}
+
+/// Wait for a permit of every limit.
+async fn acquire_permits(limits: &[Arc<Semaphore>]) -> Vec<OwnedSemaphorePermit> {
+    let mut permits = Vec::with_capacity(limits.len());
+    for limit in limits {
+        // Limits are never closed, so acquiring only fails once the proxy is
+        // gone.
+        if let Ok(permit) = limit.clone().acquire_owned().await {
+            permits.push(permit);
+        }
     }
+
+    permits
+}
+
+/// Take a permit of every limit, or none if any limit is reached.
+fn try_acquire_permits(limits: &[Arc<Semaphore>]) -> Option<Vec<OwnedSemaphorePermit>> {
+    limits
+        .iter()
+        .map(|limit| limit.clone().try_acquire_owned().ok())
+        .collect()
+}
+
+/// Run an accept loop on a single threaded runtime owned by a dedicated
+/// thread, so connections accepted by it are served on the same core.
+///
+/// The runtime, along with every connection still open on it, is dropped
+/// once the accept loop finishes or is aborted.
+///
+/// Improvement(s):
+/// - Pin acceptor threads to distinct cores.
+fn spawn_on_dedicated_runtime<F>(listener: usize, handler: F) -> io::Result<JoinHandle<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let runtime = Builder::new_current_thread().enable_all().build()?;
+    let (done_tx, done_rx) = oneshot::channel::<()>();
+    let handle = runtime.spawn(async move {
+        let _done = done_tx;
+        handler.await
+    });
+
+    std::thread::Builder::new()
+        .name(format!("fproxy-acceptor-{listener}"))
+        .spawn(move || {
+            runtime.block_on(async move {
+                let _ = done_rx.await;
+            })
+        })?;
+
+    Ok(handle)
 }
 
+/// Dropping a proxy only signals its accept loops to stop, and lets open
+/// sessions drain in the background. Use [`Proxy::shutdown`] to wait for
+/// them.
 impl Drop for Proxy {
     fn drop(&mut self) {
-        self.shutdown();
+        let _ = self.tx.send(Signal::SIGTERM);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Listener;
+    use super::Proxy;
+    use super::ProxyConfig;
+    use super::Route;
+    use super::Router;
+    use crate::config::LimitPolicy;
+    use crate::config::TargetAddr;
+    use crate::dns::default_async_dns_resolver;
+    use crate::strategy::RoundRobinStrategy;
+    use socket2::Domain;
+    use socket2::Socket;
+    use socket2::Type;
+    use std::net::SocketAddr;
+    use std::os::fd::AsRawFd;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::time::Instant;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+    use tokio::net::UnixListener;
+    use tokio::net::UnixStream;
+    use tokio::sync::Semaphore;
+    use tokio::time::timeout;
+
+    /// Start a TCP echo server, returning its address.
+    async fn echo_target() -> TargetAddr {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = target.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        TargetAddr::Inet {
+            addr: "127.0.0.1".to_owned(),
+            port,
+        }
+    }
+
+    /// Send a ping through the proxy, returning whether it was echoed back.
+    async fn ping(client: &mut TcpStream) -> bool {
+        let mut buf = [0; 4];
+        client.write_all(b"ping").await.is_ok()
+            && timeout(Duration::from_secs(1), client.read_exact(&mut buf))
+                .await
+                .is_ok_and(|result| result.is_ok())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unix_listener_to_unix_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target_path = dir.path().join("target.sock");
+        let target = UnixListener::bind(&target_path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        let proxy_path = dir.path().join("proxy.sock");
+        let route = Route::builder()
+            .app("sidecar".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![TargetAddr::Unix(
+                target_path,
+            )])))
+            .build();
+        let _proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(vec![Listener::Unix(
+                    UnixListener::bind(&proxy_path).unwrap(),
+                )])
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .build(),
+        );
+
+        let mut client = UnixStream::connect(&proxy_path).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_idle_connections_are_closed() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let route = Route::builder()
+            .app("idle".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![TargetAddr::Inet {
+                addr: "127.0.0.1".to_owned(),
+                port: target_port,
+            }])))
+            .idle_timeout(Some(Duration::from_millis(200)))
+            .build();
+        let _proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(vec![Listener::Tcp(listener)])
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .build(),
+        );
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+
+        let started = Instant::now();
+        let len = timeout(Duration::from_secs(5), client.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(len, 0);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connection_limit_policies() {
+        for policy in [LimitPolicy::Reject, LimitPolicy::Pause] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let route = Route::builder()
+                .app("limited".to_owned())
+                .target_resolver(Arc::new(RoundRobinStrategy::new(vec![echo_target().await])))
+                .build();
+            let _proxy = Proxy::listen(
+                ProxyConfig::builder()
+                    .listeners(vec![Listener::Tcp(listener)])
+                    .dns_resolver(default_async_dns_resolver().await.unwrap())
+                    .router(Router::App(Arc::new(route)))
+                    .connection_limits(vec![Arc::new(Semaphore::new(1))])
+                    .connection_limit_policy(policy)
+                    .build(),
+            );
+
+            let mut first = TcpStream::connect(address).await.unwrap();
+            assert!(ping(&mut first).await);
+
+            let mut second = TcpStream::connect(address).await.unwrap();
+            assert!(!ping(&mut second).await);
+
+            drop(first);
+            match policy {
+                // The paused connection is picked up once a slot frees up.
+                LimitPolicy::Pause => assert!(ping(&mut second).await),
+                // New connections are accepted again once the proxy has seen
+                // the first one close.
+                LimitPolicy::Reject => {
+                    let mut accepted = false;
+                    for _ in 0..10 {
+                        let mut third = TcpStream::connect(address).await.unwrap();
+                        if ping(&mut third).await {
+                            accepted = true;
+                            break;
+                        }
+                    }
+                    assert!(accepted);
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_acceptors_on_dedicated_runtimes() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = target.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                });
+            }
+        });
+
+        let mut listeners = Vec::new();
+        let mut addresses = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addresses.push(listener.local_addr().unwrap());
+            listeners.push(Listener::Tcp(listener));
+        }
+
+        let route = Route::builder()
+            .app("sharded".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![TargetAddr::Inet {
+                addr: "127.0.0.1".to_owned(),
+                port: target_port,
+            }])))
+            .build();
+        let _proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(listeners)
+                .per_core_runtime(true)
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .build(),
+        );
+
+        for address in addresses {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sessions_are_drained_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let route = Route::builder()
+            .app("draining".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![echo_target().await])))
+            .build();
+        let proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(vec![Listener::Tcp(listener)])
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .shutdown_timeout(Duration::from_millis(500))
+                .build(),
+        );
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        assert!(ping(&mut client).await);
+
+        let shutdown = tokio::spawn(proxy.shutdown());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The open session is still served while draining.
+        assert!(ping(&mut client).await);
+
+        // And closed once the shutdown timeout expires.
+        let report = shutdown.await.unwrap();
+        assert_eq!((report.drained, report.closed, report.aborted), (0, 1, 0));
+        let mut buf = [0; 4];
+        let closed = timeout(Duration::from_secs(1), client.read(&mut buf)).await;
+        assert!(closed.is_ok_and(|result| result.map_or(true, |len| len == 0)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replacement_serves_new_connections_while_draining() {
+        let address = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let reusable = |address: SocketAddr| {
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+            socket.set_reuse_port(true).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            socket.bind(&address.into()).unwrap();
+            socket.listen(128).unwrap();
+            Listener::Tcp(TcpListener::from_std(socket.into()).unwrap())
+        };
+        let target = echo_target().await;
+        let proxy = |listener| async {
+            let route = Route::builder()
+                .app("rolled-out".to_owned())
+                .target_resolver(Arc::new(RoundRobinStrategy::new(vec![target.clone()])))
+                .build();
+            Proxy::listen(
+                ProxyConfig::builder()
+                    .listeners(vec![listener])
+                    .dns_resolver(default_async_dns_resolver().await.unwrap())
+                    .router(Router::App(Arc::new(route)))
+                    .shutdown_timeout(Duration::from_secs(2))
+                    .build(),
+            )
+        };
+
+        let retired = reusable(address);
+        let Listener::Tcp(listener) = &retired else {
+            unreachable!()
+        };
+        let address = listener.local_addr().unwrap();
+        let retired = proxy(retired).await;
+
+        // An open session keeps the retired proxy draining.
+        let mut open = TcpStream::connect(address).await.unwrap();
+        assert!(ping(&mut open).await);
+
+        let _replacement = proxy(reusable(address)).await;
+        let shutdown = tokio::spawn(retired.shutdown());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for _ in 0..32 {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            assert!(ping(&mut client).await);
+        }
+
+        assert!(!shutdown.is_finished());
+        assert!(ping(&mut open).await);
+    }
+
+    // A single threaded runtime, so that a splice blocking on the target
+    // would hold up every other task.
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_splicing_to_slow_target_does_not_block_runtime() {
+        let target = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        let reader = std::thread::spawn(move || {
+            let (mut stream, _) = target.accept().unwrap();
+            std::thread::sleep(Duration::from_secs(2));
+            let mut received = Vec::new();
+            std::io::Read::read_to_end(&mut stream, &mut received).unwrap();
+            received.len()
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let route = Route::builder()
+            .app("spliced".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![TargetAddr::Inet {
+                addr: "127.0.0.1".to_owned(),
+                port: target_port,
+            }])))
+            .splice(true)
+            .build();
+        let _proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(vec![Listener::Tcp(listener)])
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .build(),
+        );
+
+        let payload = vec![7; 32 * 1024 * 1024];
+        let len = payload.len();
+        let writer = tokio::spawn(async move {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(&payload).await.unwrap();
+            client.shutdown().await.unwrap();
+            client
+        });
+
+        // Timers keep firing while the target isn't reading.
+        let started = Instant::now();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+
+        let _client = writer.await.unwrap();
+        let received = tokio::task::spawn_blocking(move || reader.join().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(received, len);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_acceptors_are_restarted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let route = Route::builder()
+            .app("restarted".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![echo_target().await])))
+            .build();
+        let mut proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(vec![Listener::Tcp(listener)])
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .build(),
+        );
+        assert!(proxy.failed_acceptors().is_empty());
+
+        proxy.acceptors[0].handle.abort();
+        while !proxy.acceptors[0].handle.is_finished() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let failed = proxy.failed_acceptors();
+        assert_eq!(failed.len(), 1);
+        assert!(proxy
+            .restart_acceptor(failed[0].listener, 1)
+            .is_some_and(|outcome| outcome.is_err()));
+        assert!(proxy.failed_acceptors().is_empty());
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        assert!(ping(&mut client).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sessions_outlive_failed_acceptors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let fd = listener.as_raw_fd();
+        let route = Route::builder()
+            .app("failing".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![echo_target().await])))
+            .build();
+        let mut proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(vec![Listener::Tcp(listener)])
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .shutdown_timeout(Duration::from_millis(200))
+                .build(),
+        );
+
+        let mut open = TcpStream::connect(address).await.unwrap();
+        assert!(ping(&mut open).await);
+
+        // Accepting on a listener shut down for reading fails with EINVAL,
+        // so the accept loop keeps failing, however often it's restarted.
+        // SAFETY: the listener is owned by the proxy, which is still alive.
+        assert_eq!(unsafe { libc::shutdown(fd, libc::SHUT_RD) }, 0);
+        for restarts in 1..=2 {
+            while proxy.failed_acceptors().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert!(ping(&mut open).await);
+            assert!(proxy
+                .restart_acceptor(0, restarts)
+                .is_some_and(|outcome| outcome.is_ok()));
+        }
+
+        // The session was handed from one accept loop to the next, and is
+        // only closed once the proxy shuts down.
+        assert!(ping(&mut open).await);
+        let report = proxy.shutdown().await;
+        assert_eq!((report.drained, report.closed, report.aborted), (0, 1, 0));
     }
 }
@@ -0,0 +1,381 @@
+use crate::config::ClientRateLimit;
+use ipnet::IpNet;
+use log::debug;
+use lru_cache::LruCache;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+use std::task::ready;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::io::AsyncRead;
+use tokio::io::ReadBuf;
+use tokio::time::sleep;
+use tokio::time::Sleep;
+
+/// Most clients tracked at once by a [`ClientRateLimiter`].
+const MAX_TRACKED_CLIENTS: usize = 64 * 1024;
+
+/// Token bucket holding up to `burst` tokens, refilled at `rate` tokens per
+/// second.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket.
+    pub(crate) fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take `amount` tokens if the bucket holds enough of them.
+    pub(crate) fn try_take(&mut self, amount: f64) -> bool {
+        self.refill();
+        if self.tokens < amount {
+            return false;
+        }
+
+        self.tokens -= amount;
+        true
+    }
+
+    /// Take `amount` tokens, going into debt if the bucket doesn't hold
+    /// enough of them.
+    pub(crate) fn take(&mut self, amount: f64) {
+        self.refill();
+        self.tokens -= amount;
+    }
+
+    /// Time until the bucket is out of debt.
+    pub(crate) fn wait_time(&mut self) -> Duration {
+        self.refill();
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Returns `true` if the bucket refilled completely, making it
+    /// indistinguishable from a new one.
+    pub(crate) fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+
+    /// Change the rate and size of the bucket, keeping the tokens already
+    /// taken.
+    pub(crate) fn set_rate(&mut self, rate: f64, burst: f64) {
+        self.refill();
+        self.rate = rate;
+        self.burst = burst;
+        self.tokens = self.tokens.min(burst);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+}
+
+/// Limits the rate of new connections per client address, with a token
+/// bucket for every client network prefix.
+///
+/// Once `MAX_TRACKED_CLIENTS` networks are tracked, the least recently seen
+/// one makes room for a new network if its bucket refilled completely,
+/// since it's then the same as untracked. Otherwise, new networks are
+/// turned away until one does, so clients spread over many networks can't
+/// get past the limit.
+#[derive(Debug)]
+pub struct ClientRateLimiter {
+    limit: ClientRateLimit,
+    buckets: Mutex<LruCache<IpNet, TokenBucket>>,
+}
+
+impl ClientRateLimiter {
+    /// Create a limiter with no client tracked yet.
+    pub fn new(limit: &ClientRateLimit) -> Self {
+        Self::with_capacity(limit, MAX_TRACKED_CLIENTS)
+    }
+
+    /// Create a limiter tracking up to `max_clients` networks at once.
+    pub(crate) fn with_capacity(limit: &ClientRateLimit, max_clients: usize) -> Self {
+        Self {
+            limit: limit.clone(),
+            buckets: Mutex::new(LruCache::new(max_clients)),
+        }
+    }
+
+    /// Limit the limiter enforces.
+    pub fn limit(&self) -> &ClientRateLimit {
+        &self.limit
+    }
+
+    /// Take a token for a new connection from the client, returning whether
+    /// the connection may proceed.
+    pub fn try_acquire(&self, client: IpAddr) -> bool {
+        let client = client.to_canonical();
+        let prefix = match client {
+            IpAddr::V4(_) => self.limit.ipv4_prefix,
+            IpAddr::V6(_) => self.limit.ipv6_prefix,
+        };
+
+        // Prefixes are validated with the app config.
+        let Ok(network) = IpNet::new(client, prefix).map(|network| network.trunc()) else {
+            return true;
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(bucket) = buckets.get_mut(&network) {
+            return bucket.try_take(1.0);
+        }
+
+        if buckets.len() >= buckets.capacity() {
+            let refilled = buckets
+                .iter_mut()
+                .next()
+                .is_some_and(|(_, bucket)| bucket.is_full());
+            if !refilled {
+                debug!("too many clients rate limited, rejecting {network}");
+                return false;
+            }
+            buckets.remove_lru();
+        }
+
+        let mut bucket = TokenBucket::new(self.limit.rate, self.limit.burst as f64);
+        let acquired = bucket.try_take(1.0);
+        buckets.insert(network, bucket);
+        acquired
+    }
+}
+
+/// Limits the throughput of data read from streams, both for each stream
+/// and for all streams throttled by the limiter combined.
+///
+/// Buckets hold a second worth of bytes, so a stream idle for a while may
+/// briefly burst at twice the rate.
+#[derive(Debug, Default)]
+pub struct BandwidthLimiter {
+    per_stream: Option<u64>,
+    shared: Option<SharedBandwidth>,
+}
+
+impl BandwidthLimiter {
+    /// Create a limiter throttling each stream to `per_stream` bytes per
+    /// second, and all of them combined to the `shared` cap.
+    pub fn new(per_stream: Option<u64>, shared: Option<SharedBandwidth>) -> Self {
+        Self { per_stream, shared }
+    }
+
+    /// Returns `true` if the limiter never throttles.
+    pub fn is_unlimited(&self) -> bool {
+        self.per_stream.is_none() && self.shared.is_none()
+    }
+
+    /// Wrap a reader so data is read from it no faster than the limits.
+    pub(crate) fn throttle<R>(&self, reader: R) -> Throttled<R> {
+        let own = self
+            .per_stream
+            .map(|rate| Arc::new(Mutex::new(bytes_bucket(rate))));
+        let shared = self.shared.as_ref().map(|shared| shared.0.clone());
+        let buckets = own.into_iter().chain(shared).collect();
+        Throttled {
+            inner: reader,
+            buckets,
+            delay: None,
+        }
+    }
+}
+
+/// Throughput cap in bytes per second shared by many streams.
+#[derive(Debug, Clone)]
+pub struct SharedBandwidth(Arc<Mutex<TokenBucket>>);
+
+impl SharedBandwidth {
+    /// Create a cap of `rate` bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self(Arc::new(Mutex::new(bytes_bucket(rate))))
+    }
+
+    /// Change the rate, keeping the bytes already sent by the streams.
+    pub fn set_rate(&self, rate: u64) {
+        lock(&self.0).set_rate(rate as f64, rate as f64);
+    }
+}
+
+fn bytes_bucket(rate: u64) -> TokenBucket {
+    TokenBucket::new(rate as f64, rate as f64)
+}
+
+/// Reader throttled by a [`BandwidthLimiter`].
+///
+/// Reads wait until every bucket is out of debt, are capped at the smallest
+/// bucket size, and then charge the bytes read to every bucket.
+pub(crate) struct Throttled<R> {
+    inner: R,
+    buckets: Vec<Arc<Mutex<TokenBucket>>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> Throttled<R> {
+    fn wait_time(&self) -> Duration {
+        self.buckets
+            .iter()
+            .map(|bucket| lock(bucket).wait_time())
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn max_read(&self) -> usize {
+        self.buckets
+            .iter()
+            .map(|bucket| lock(bucket).burst as usize)
+            .min()
+            .unwrap_or(usize::MAX)
+            .max(1)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buckets.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        loop {
+            if let Some(delay) = &mut this.delay {
+                ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+            }
+
+            let wait = this.wait_time();
+            if wait.is_zero() {
+                break;
+            }
+
+            this.delay = Some(Box::pin(sleep(wait)));
+        }
+
+        let max_read = this.max_read().min(buf.remaining());
+        let mut limited = buf.take(max_read);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let len = limited.filled().len();
+
+        // SAFETY: `limited` is a view over the unfilled part of `buf`, and
+        // the reader initialized the `len` bytes it filled.
+        unsafe { buf.assume_init(len) };
+        buf.advance(len);
+
+        for bucket in &this.buckets {
+            lock(bucket).take(len as f64);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Lock a bucket, ignoring poisoning since buckets are valid at all times.
+fn lock(bucket: &Mutex<TokenBucket>) -> MutexGuard<'_, TokenBucket> {
+    bucket
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::BandwidthLimiter;
+    use super::ClientRateLimiter;
+    use super::SharedBandwidth;
+    use crate::config::ClientRateLimit;
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use std::time::Instant;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_clients_are_limited_per_prefix() {
+        let limit: ClientRateLimit =
+            serde_json::from_str(r#"{"Rate": 0.001, "Burst": 2, "Ipv4Prefix": 24}"#).unwrap();
+        let limiter = ClientRateLimiter::new(&limit);
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert!(limiter.try_acquire(ip("10.0.0.1")));
+        assert!(limiter.try_acquire(ip("10.0.0.2")));
+        assert!(!limiter.try_acquire(ip("10.0.0.3")));
+
+        // IPv4 clients on a dual-stack listener share the IPv4 bucket.
+        assert!(!limiter.try_acquire(ip("::ffff:10.0.0.4")));
+        assert!(limiter.try_acquire(ip("10.0.1.1")));
+
+        // IPv6 clients are grouped per /64 by default.
+        assert!(limiter.try_acquire(ip("2001:db8::1")));
+        assert!(limiter.try_acquire(ip("2001:db8::2")));
+        assert!(!limiter.try_acquire(ip("2001:db8::3")));
+        assert!(limiter.try_acquire(ip("2001:db8:0:1::1")));
+    }
+
+    #[test]
+    fn test_new_clients_are_rejected_while_full() {
+        let limit: ClientRateLimit = serde_json::from_str(r#"{"Rate": 50, "Burst": 1}"#).unwrap();
+        let limiter = ClientRateLimiter::with_capacity(&limit, 2);
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert!(limiter.try_acquire(ip("10.0.0.1")));
+        assert!(limiter.try_acquire(ip("10.0.0.2")));
+        assert!(!limiter.try_acquire(ip("10.0.0.3")));
+
+        // Once the least recently seen client's bucket refills, it makes
+        // room for a new one.
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(limiter.try_acquire(ip("10.0.0.2")));
+        assert!(limiter.try_acquire(ip("10.0.0.3")));
+        assert!(!limiter.try_acquire(ip("10.0.0.4")));
+    }
+
+    #[tokio::test]
+    async fn test_reads_are_throttled() {
+        const RATE: u64 = 256 * 1024;
+        let data = vec![0; 2 * RATE as usize];
+
+        // The first second worth of bytes is read right away.
+        let begin = Instant::now();
+        let limiter = BandwidthLimiter::new(Some(RATE), None);
+        let mut reader = limiter.throttle(&data[..]);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read.len(), data.len());
+        assert!(begin.elapsed() >= Duration::from_millis(900));
+
+        // Streams of the same limiter share the app rate.
+        let begin = Instant::now();
+        let limiter = BandwidthLimiter::new(None, Some(SharedBandwidth::new(RATE)));
+        let half = &data[..RATE as usize];
+        let (mut first, mut second) = (limiter.throttle(half), limiter.throttle(half));
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        let (a_len, b_len) = tokio::join!(first.read_to_end(&mut a), second.read_to_end(&mut b));
+        assert_eq!(a_len.unwrap() + b_len.unwrap(), data.len());
+        assert!(begin.elapsed() >= Duration::from_millis(900));
+    }
+}
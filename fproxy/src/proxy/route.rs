@@ -0,0 +1,180 @@
+use crate::config::App;
+use crate::config::SocketOptions;
+use crate::config::TargetAddr;
+use crate::proxy::AccessList;
+use crate::proxy::BandwidthLimiter;
+use crate::proxy::ClientRateLimiter;
+use crate::proxy::Generation;
+use crate::strategy::Strategy;
+use crate::tls::TlsTerminator;
+use crate::tls::UpstreamTls;
+use dashmap::DashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use typed_builder::TypedBuilder;
+
+/// Where an accepted connection for an app gets forwarded to.
+#[derive(TypedBuilder)]
+pub struct Route {
+    /// App the route belongs to.
+    pub app: App,
+
+    /// Configuration rollout the route was created by.
+    #[builder(default)]
+    pub generation: Generation,
+
+    /// Strategy for resolving what target to connect to.
+    pub target_resolver: Arc<dyn Strategy<Item = TargetAddr>>,
+
+    /// Terminate TLS from clients before forwarding to targets.
+    #[builder(default)]
+    pub tls_terminator: Option<Arc<TlsTerminator>>,
+
+    /// Originate TLS from the proxy to targets.
+    #[builder(default)]
+    pub upstream_tls: Option<Arc<UpstreamTls>>,
+
+    /// Options for client connections once routed, and target connections.
+    #[builder(default)]
+    pub socket_options: SocketOptions,
+
+    /// Forward plain TCP sessions with `splice(2)`.
+    #[builder(default)]
+    pub splice: bool,
+
+    /// Close sessions with no data in either direction for this long.
+    #[builder(default)]
+    pub idle_timeout: Option<Duration>,
+
+    /// Close sessions open for longer than this.
+    #[builder(default)]
+    pub max_connection_duration: Option<Duration>,
+
+    /// Close sessions this long after one side stopped sending, if the
+    /// other side hasn't stopped too.
+    #[builder(default)]
+    pub half_close_timeout: Option<Duration>,
+
+    /// Reset both legs (`SO_LINGER` 0) when the proxy cuts a session short,
+    /// instead of closing them gracefully.
+    #[builder(default)]
+    pub abortive_close: bool,
+
+    /// Connections of the app open at once, enforced once a connection has
+    /// been routed to the app.
+    #[builder(default)]
+    pub connection_limit: Option<Arc<Semaphore>>,
+
+    /// Networks clients of the app may, or may not, connect from.
+    #[builder(default)]
+    pub access_list: Option<Arc<AccessList>>,
+
+    /// Rate of new connections accepted from each client address.
+    #[builder(default)]
+    pub client_rate_limit: Option<Arc<ClientRateLimiter>>,
+
+    /// Throughput of data sent by clients to targets.
+    #[builder(default)]
+    pub upload_limit: BandwidthLimiter,
+
+    /// Throughput of data sent by targets to clients.
+    #[builder(default)]
+    pub download_limit: BandwidthLimiter,
+}
+
+impl Debug for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Route")
+            .field("app", &self.app)
+            .field("generation", &self.generation)
+            .field("tls_terminator", &self.tls_terminator.is_some())
+            .field("upstream_tls", &self.upstream_tls.is_some())
+            .field("socket_options", &self.socket_options)
+            .field("splice", &self.splice)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_connection_duration", &self.max_connection_duration)
+            .field("half_close_timeout", &self.half_close_timeout)
+            .field("abortive_close", &self.abortive_close)
+            .field("connection_limit", &self.connection_limit)
+            .field("access_list", &self.access_list)
+            .field("client_rate_limit", &self.client_rate_limit)
+            .field("upload_limit", &self.upload_limit)
+            .field("download_limit", &self.download_limit)
+            .finish()
+    }
+}
+
+/// Decides which route an accepted connection takes.
+#[derive(Debug, Clone)]
+pub enum Router {
+    /// Every connection on the listener belongs to a single app.
+    App(Arc<Route>),
+
+    /// The listener is shared by multiple apps, and connections are routed
+    /// by the server name in the TLS ClientHello without terminating TLS.
+    ServerName(Arc<ServerNameRouter>),
+}
+
+/// Routes TLS connections by server name (SNI).
+///
+/// Names are matched exactly first, and then against a wildcard entry
+/// for the parent domain (e.g. `*.example.com`).
+#[derive(Debug, Default)]
+pub struct ServerNameRouter {
+    routes: DashMap<String, Arc<Route>>,
+}
+
+impl ServerNameRouter {
+    /// Find the route for a server name sent by a client.
+    pub fn resolve(&self, server_name: &str) -> Option<Arc<Route>> {
+        if let Some(route) = self.routes.get(server_name) {
+            return Some(route.clone());
+        }
+
+        let (_, parent) = server_name.split_once('.')?;
+        self.routes
+            .get(&format!("*.{parent}"))
+            .map(|route| route.clone())
+    }
+
+    /// App currently serving a server name, if any.
+    pub fn app_for(&self, server_name: &str) -> Option<App> {
+        self.routes.get(server_name).map(|route| route.app.clone())
+    }
+
+    /// Point all server names at the route, and drop names previously
+    /// served by the same app that are not in `server_names`.
+    ///
+    /// New names are inserted before stale ones are removed, so there is
+    /// no window where a name still in use has no route.
+    pub fn replace_app(&self, route: Arc<Route>, server_names: &[String]) {
+        for name in server_names {
+            self.routes.insert(name.to_ascii_lowercase(), route.clone());
+        }
+
+        self.routes.retain(|name, existing| {
+            existing.app != route.app || server_names.iter().any(|n| n.eq_ignore_ascii_case(name))
+        });
+    }
+
+    /// Remove every server name served by the app. Returns `true` if the app
+    /// had any.
+    pub fn remove_app(&self, app: &str) -> bool {
+        let routes = self.routes.len();
+        self.routes.retain(|_, route| route.app != app);
+        self.routes.len() != routes
+    }
+
+    /// Returns `true` if no app other than `app` is routed through this
+    /// router.
+    pub fn routes_only(&self, app: &str) -> bool {
+        self.routes.iter().all(|route| route.app == app)
+    }
+
+    /// Returns `true` if no app is routed through this router.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
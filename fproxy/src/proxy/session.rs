@@ -0,0 +1,381 @@
+use crate::proxy::splice::splice_directions;
+use crate::proxy::AsTcpStream;
+use crate::proxy::Route;
+use crate::proxy::TargetStream;
+use futures::future::select;
+use futures::future::Either;
+use log::debug;
+use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::pin::pin;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::io::copy;
+use tokio::io::split;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::ReadBuf;
+use tokio::time::sleep;
+use tokio::time::timeout;
+
+/// Why a proxied session was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Both sides finished sending.
+    Completed,
+
+    /// One side finished sending, and the other didn't follow within the
+    /// app's half-close timeout.
+    HalfCloseTimeout,
+
+    /// Reading from or writing to either side failed.
+    Error,
+
+    /// No data was seen in either direction for the app's idle timeout.
+    IdleTimeout,
+
+    /// The session outlived the app's maximum connection duration.
+    MaxConnectionDuration,
+}
+
+/// Last time traffic was seen in either direction of a session.
+pub(crate) struct Activity {
+    started: Instant,
+    last_active_ms: AtomicU64,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last_active_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    pub(crate) fn idle(&self) -> Duration {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        Duration::from_millis(elapsed.saturating_sub(self.last_active_ms.load(Ordering::Relaxed)))
+    }
+}
+
+/// Forward data between a client and a target until both have finished
+/// sending, at no more than the app's bandwidth limits.
+///
+/// Once one side sends FIN, the write half of the other side is shut down,
+/// while data keeps flowing in the reverse direction until that side closes
+/// too, or the half-close timeout expires. An error in either direction
+/// ends the session right away.
+pub(crate) async fn forward_session<S>(
+    incoming: &mut S,
+    target: &mut TargetStream,
+    route: &Route,
+    activity: &Activity,
+) -> io::Result<CloseReason>
+where
+    S: AsyncRead + AsyncWrite + AsTcpStream + Unpin,
+{
+    let unlimited = route.upload_limit.is_unlimited() && route.download_limit.is_unlimited();
+    if let (true, Some(client), Some(server)) = (
+        route.splice && unlimited,
+        incoming.as_tcp_stream(),
+        target.as_tcp_stream(),
+    ) {
+        match splice_directions(client, server, activity) {
+            Ok((upload, download)) => {
+                return join_directions(upload, download, route.half_close_timeout).await
+            }
+            Err(error) => debug!("failed to splice, copying instead: {error}"),
+        }
+    }
+
+    let (client_read, mut client_write) = split(incoming);
+    let (target_read, mut target_write) = split(target);
+    let client_read = route.upload_limit.throttle(client_read);
+    let target_read = route.download_limit.throttle(target_read);
+    let upload = copy_one_way(Tracked::new(client_read, activity), &mut target_write);
+    let download = copy_one_way(Tracked::new(target_read, activity), &mut client_write);
+    join_directions(upload, download, route.half_close_timeout).await
+}
+
+/// Copy data until the reader reaches EOF, then shut down the writer.
+async fn copy_one_way<R, W>(mut reader: R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let len = copy(&mut reader, writer).await?;
+    writer.shutdown().await?;
+    Ok(len)
+}
+
+/// Wait for both directions of a session, giving the second direction up to
+/// `half_close_timeout` once the first has finished.
+async fn join_directions<A, B>(
+    upload: A,
+    download: B,
+    half_close_timeout: Option<Duration>,
+) -> io::Result<CloseReason>
+where
+    A: Future<Output = io::Result<u64>>,
+    B: Future<Output = io::Result<u64>>,
+{
+    let (upload, download) = (pin!(upload), pin!(download));
+    let (finished, remaining) = match select(upload, download).await {
+        Either::Left((result, download)) => (result, Either::Right(download)),
+        Either::Right((result, upload)) => (result, Either::Left(upload)),
+    };
+    finished?;
+
+    let Some(half_close_timeout) = half_close_timeout else {
+        return remaining.await.map(|_| CloseReason::Completed);
+    };
+
+    match timeout(half_close_timeout, remaining).await {
+        Ok(result) => result.map(|_| CloseReason::Completed),
+        Err(_) => Ok(CloseReason::HalfCloseTimeout),
+    }
+}
+
+/// Resolves once no activity has been seen for `timeout`, and never if no
+/// timeout is set.
+pub(crate) async fn idle_for(activity: &Activity, timeout: Option<Duration>) {
+    let Some(timeout) = timeout else {
+        return std::future::pending().await;
+    };
+
+    loop {
+        let idle = activity.idle();
+        if idle >= timeout {
+            return;
+        }
+
+        sleep(timeout - idle).await;
+    }
+}
+
+/// Resolves after `duration`, and never if no duration is set.
+pub(crate) async fn expire_after(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Stream wrapper recording reads on a session's activity.
+pub(crate) struct Tracked<'a, S> {
+    inner: S,
+    activity: &'a Activity,
+}
+
+impl<'a, S> Tracked<'a, S> {
+    pub(crate) fn new(inner: S, activity: &'a Activity) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.activity.touch();
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::TargetAddr;
+    use crate::dns::default_async_dns_resolver;
+    use crate::proxy::Listener;
+    use crate::proxy::Proxy;
+    use crate::proxy::ProxyConfig;
+    use crate::proxy::Route;
+    use crate::proxy::Router;
+    use crate::strategy::RoundRobinStrategy;
+    use std::io::ErrorKind;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+
+    /// Client and target connected through a proxy.
+    struct Session {
+        client: TcpStream,
+        target: TcpStream,
+        _proxy: Proxy,
+    }
+
+    async fn session(
+        splice: bool,
+        half_close_timeout: Option<Duration>,
+        abortive: bool,
+    ) -> Session {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let route = Route::builder()
+            .app("half-close".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![TargetAddr::Inet {
+                addr: "127.0.0.1".to_owned(),
+                port: target.local_addr().unwrap().port(),
+            }])))
+            .splice(splice)
+            .half_close_timeout(half_close_timeout)
+            .abortive_close(abortive)
+            .build();
+        let proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(vec![Listener::Tcp(listener)])
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .build(),
+        );
+
+        let client = TcpStream::connect(address).await.unwrap();
+        let (target, _) = target.accept().await.unwrap();
+        Session {
+            client,
+            target,
+            _proxy: proxy,
+        }
+    }
+
+    async fn read_to_end(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+            .await
+            .expect("stream was not closed")?;
+        Ok(buf)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_client_first_close() {
+        for splice in [false, true] {
+            let Session {
+                mut client,
+                mut target,
+                _proxy,
+            } = session(splice, None, false).await;
+
+            client.write_all(b"hello").await.unwrap();
+            client.shutdown().await.unwrap();
+            assert_eq!(read_to_end(&mut target).await.unwrap(), b"hello");
+
+            // The client stopped sending, but still receives.
+            target.write_all(b"world").await.unwrap();
+            target.shutdown().await.unwrap();
+            assert_eq!(read_to_end(&mut client).await.unwrap(), b"world");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_target_first_close() {
+        for splice in [false, true] {
+            let Session {
+                mut client,
+                mut target,
+                _proxy,
+            } = session(splice, None, false).await;
+
+            target.write_all(b"bye").await.unwrap();
+            target.shutdown().await.unwrap();
+            assert_eq!(read_to_end(&mut client).await.unwrap(), b"bye");
+
+            // The target stopped sending, but still receives.
+            client.write_all(b"late").await.unwrap();
+            client.shutdown().await.unwrap();
+            assert_eq!(read_to_end(&mut target).await.unwrap(), b"late");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reset_is_propagated() {
+        for splice in [false, true] {
+            let Session {
+                client,
+                mut target,
+                _proxy,
+            } = session(splice, None, false).await;
+
+            client.set_linger(Some(Duration::ZERO)).unwrap();
+            drop(client);
+
+            let error = read_to_end(&mut target).await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_half_close_timeout() {
+        let timeout = Some(Duration::from_millis(200));
+        let Session {
+            mut client,
+            mut target,
+            _proxy,
+        } = session(false, timeout, false).await;
+
+        client.shutdown().await.unwrap();
+        assert!(read_to_end(&mut target).await.unwrap().is_empty());
+
+        // The target never closes, so the proxy does once the timeout expires.
+        assert!(read_to_end(&mut client).await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_abortive_close() {
+        let timeout = Some(Duration::from_millis(200));
+        let Session {
+            mut client,
+            mut target,
+            _proxy,
+        } = session(false, timeout, true).await;
+
+        client.shutdown().await.unwrap();
+        assert!(read_to_end(&mut target).await.unwrap().is_empty());
+
+        let error = read_to_end(&mut client).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    }
+}
@@ -0,0 +1,276 @@
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+use tokio::time::timeout;
+
+/// TLS record content type for handshake messages.
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+/// TLS handshake message type for a ClientHello.
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+
+/// TLS extension type for server name indication.
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+
+/// Server name type for DNS hostnames.
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Size of a TLS record header.
+const RECORD_HEADER_LEN: usize = 5;
+
+/// Delay between peeks while waiting for the rest of a ClientHello.
+const PEEK_RETRY_DELAY: Duration = Duration::from_millis(5);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ParseClientHelloError {
+    #[error("client hello is incomplete")]
+    Incomplete,
+    #[error("connection is not a tls handshake")]
+    NotTls,
+    #[error("client hello is malformed")]
+    Malformed,
+    #[error("client hello exceeds the peek buffer")]
+    TooLarge,
+    #[error("timed out waiting for client hello")]
+    Timeout,
+    #[error("connection closed before client hello was received")]
+    Closed,
+}
+
+/// Peek the TLS ClientHello on an accepted stream and return the server
+/// name the client asked for, without consuming any bytes from the stream.
+///
+/// The ClientHello usually arrives in a single segment, so rather than
+/// waiting on readiness (which stays set while unread bytes are queued)
+/// we re-peek after a short delay until the message is complete.
+pub(crate) async fn peek_server_name(
+    stream: &TcpStream,
+    max_len: usize,
+    wait: Duration,
+) -> Result<Option<String>, ParseClientHelloError> {
+    let peek = async {
+        let mut buf = vec![0; max_len];
+        loop {
+            let len = stream
+                .peek(&mut buf)
+                .await
+                .map_err(|_| ParseClientHelloError::Closed)?;
+
+            if len == 0 {
+                return Err(ParseClientHelloError::Closed);
+            }
+
+            match parse_server_name(&buf[..len]) {
+                Err(ParseClientHelloError::Incomplete) if len == max_len => {
+                    return Err(ParseClientHelloError::TooLarge)
+                }
+                Err(ParseClientHelloError::Incomplete) => sleep(PEEK_RETRY_DELAY).await,
+                result => return result,
+            }
+        }
+    };
+
+    timeout(wait, peek)
+        .await
+        .map_err(|_| ParseClientHelloError::Timeout)?
+}
+
+/// Extract the SNI host name from the raw bytes of a TLS ClientHello.
+///
+/// The handshake message may be fragmented over multiple TLS records, in
+/// which case the fragments are reassembled before parsing.
+pub(crate) fn parse_server_name(buf: &[u8]) -> Result<Option<String>, ParseClientHelloError> {
+    if buf.first().is_some_and(|&t| t != CONTENT_TYPE_HANDSHAKE) {
+        return Err(ParseClientHelloError::NotTls);
+    }
+
+    let mut handshake = Vec::new();
+    let mut records = buf;
+    loop {
+        let header = records
+            .get(..RECORD_HEADER_LEN)
+            .ok_or(ParseClientHelloError::Incomplete)?;
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Err(ParseClientHelloError::Malformed);
+        }
+
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let fragment = records
+            .get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)
+            .ok_or(ParseClientHelloError::Incomplete)?;
+        handshake.extend_from_slice(fragment);
+        records = &records[RECORD_HEADER_LEN + len..];
+
+        if handshake.len() < 4 {
+            continue;
+        }
+
+        if handshake[0] != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return Err(ParseClientHelloError::Malformed);
+        }
+
+        let body_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if handshake.len() >= 4 + body_len {
+            return parse_client_hello(&handshake[4..4 + body_len]);
+        }
+    }
+}
+
+fn parse_client_hello(body: &[u8]) -> Result<Option<String>, ParseClientHelloError> {
+    let mut reader = Reader(body);
+
+    // Legacy version and random.
+    reader.skip(2 + 32)?;
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.skip(cipher_suites_len)?;
+    let compression_methods_len = reader.u8()? as usize;
+    reader.skip(compression_methods_len)?;
+
+    // Extensions are optional in older clients.
+    if reader.0.is_empty() {
+        return Ok(None);
+    }
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let extension = extensions.take(extension_len)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader(extension);
+        let list_len = names.u16()? as usize;
+        let mut names = Reader(names.take(list_len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name_len = names.u16()? as usize;
+            let name = names.take(name_len)?;
+            if name_type == SERVER_NAME_TYPE_HOST_NAME {
+                let name =
+                    std::str::from_utf8(name).map_err(|_| ParseClientHelloError::Malformed)?;
+                return Ok(Some(name.to_ascii_lowercase()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Cursor over a fully buffered handshake message. Running out of bytes
+/// here means the lengths in the message are inconsistent.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ParseClientHelloError> {
+        if self.0.len() < len {
+            return Err(ParseClientHelloError::Malformed);
+        }
+
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), ParseClientHelloError> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseClientHelloError> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseClientHelloError> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_server_name;
+    use super::ParseClientHelloError;
+
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![];
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            let list_len = name.len() as u16 + 3;
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&(list_len + 2).to_be_bytes());
+            extensions.extend_from_slice(&list_len.to_be_bytes());
+            extensions.push(0x00);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_server_name_works() {
+        let hello = client_hello(Some("App.Example.com"));
+        assert_eq!(
+            parse_server_name(&hello),
+            Ok(Some("app.example.com".to_owned()))
+        );
+        assert_eq!(parse_server_name(&client_hello(None)), Ok(None));
+    }
+
+    #[test]
+    fn test_parse_server_name_incomplete() {
+        let hello = client_hello(Some("app.example.com"));
+        for len in 0..hello.len() {
+            assert_eq!(
+                parse_server_name(&hello[..len]),
+                Err(ParseClientHelloError::Incomplete)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_server_name_fragmented_records() {
+        let hello = client_hello(Some("app.example.com"));
+        let (first, second) = hello[5..].split_at(10);
+
+        let mut fragmented = vec![0x16, 0x03, 0x01];
+        fragmented.extend_from_slice(&(first.len() as u16).to_be_bytes());
+        fragmented.extend_from_slice(first);
+        fragmented.extend_from_slice(&[0x16, 0x03, 0x01]);
+        fragmented.extend_from_slice(&(second.len() as u16).to_be_bytes());
+        fragmented.extend_from_slice(second);
+
+        assert_eq!(
+            parse_server_name(&fragmented),
+            Ok(Some("app.example.com".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_parse_server_name_rejects_plaintext() {
+        assert_eq!(
+            parse_server_name(b"GET / HTTP/1.1\r\n"),
+            Err(ParseClientHelloError::NotTls)
+        );
+    }
+}
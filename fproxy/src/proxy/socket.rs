@@ -0,0 +1,159 @@
+use crate::config::SocketOptions;
+use socket2::SockRef;
+use socket2::Socket;
+use socket2::TcpKeepalive;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
+use std::time::Duration;
+
+/// Apply listener options of an app to a bound TCP socket, and start
+/// listening.
+///
+/// Buffer sizes set on the listener are inherited by accepted connections,
+/// so the window scale negotiated during the handshake accounts for them.
+pub(crate) fn listen_with_options(socket: &Socket, options: &SocketOptions) -> io::Result<()> {
+    set_buffer_sizes(&SockRef::from(socket), options)?;
+    if let Some(queue_len) = options.fast_open {
+        set_fast_open(socket, queue_len)?;
+    }
+
+    socket.listen(options.backlog)
+}
+
+/// Apply options to an accepted or connecting TCP stream.
+pub(crate) fn configure_stream<S: AsRawFd>(stream: &S, options: &SocketOptions) -> io::Result<()> {
+    // SAFETY: the file descriptor is owned by `stream`, which outlives the
+    // borrow.
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+    let socket = SockRef::from(&fd);
+    socket.set_nodelay(options.no_delay)?;
+    socket.set_tcp_keepalive(&keep_alive(options))?;
+    set_buffer_sizes(&socket, options)?;
+    if let Some(timeout) = options.user_timeout {
+        set_user_timeout(&socket, timeout)?;
+    }
+
+    Ok(())
+}
+
+/// Apply options to a socket about to connect to a target.
+pub(crate) fn configure_connect(socket: &Socket, options: &SocketOptions) -> io::Result<()> {
+    configure_stream(socket, options)?;
+    if options.fast_open.is_some() {
+        set_fast_open_connect(socket)?;
+    }
+
+    Ok(())
+}
+
+fn keep_alive(options: &SocketOptions) -> TcpKeepalive {
+    TcpKeepalive::new()
+        .with_time(options.keep_alive.time)
+        .with_interval(options.keep_alive.interval)
+        .with_retries(options.keep_alive.retries)
+}
+
+fn set_buffer_sizes(socket: &SockRef<'_>, options: &SocketOptions) -> io::Result<()> {
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_user_timeout(socket: &SockRef<'_>, timeout: Duration) -> io::Result<()> {
+    socket.set_tcp_user_timeout(Some(timeout))
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open(socket: &Socket, queue_len: u32) -> io::Result<()> {
+    setsockopt(socket, libc::TCP_FASTOPEN, queue_len as libc::c_int)
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open_connect(socket: &Socket) -> io::Result<()> {
+    setsockopt(socket, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_user_timeout(_: &SockRef<'_>, _: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tcp user timeout is only supported on linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open(_: &Socket, _: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tcp fast open is only supported on linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open_connect(socket: &Socket) -> io::Result<()> {
+    set_fast_open(socket, 0)
+}
+
+/// Set an integer `IPPROTO_TCP` option socket2 doesn't expose.
+#[cfg(target_os = "linux")]
+fn setsockopt(socket: &Socket, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: the file descriptor is owned by `socket` and stays open for the
+    // duration of the call, and `value` outlives it.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::configure_stream;
+    use crate::config::SocketOptions;
+    use socket2::Socket;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_configure_stream_applies_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let options: SocketOptions = serde_json::from_str(
+            r#"{"NoDelay": true, "UserTimeout": "5s", "KeepAlive": {"Time": "30s"}}"#,
+        )
+        .unwrap();
+        configure_stream(&stream, &options).unwrap();
+
+        let socket = Socket::from(stream.into_std().unwrap());
+        assert!(socket.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_retries().unwrap(), 9);
+        assert_eq!(
+            socket.tcp_user_timeout().unwrap(),
+            Some(Duration::from_secs(5))
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::proxy::session::Activity;
+use log::debug;
+#[cfg(target_os = "linux")]
+use std::future::Future;
+#[cfg(not(target_os = "linux"))]
+use std::future::Ready;
+use std::io;
+use tokio::io::copy_bidirectional;
+use tokio::net::TcpStream;
+
+/// Copy data in both directions between two TCP streams using `splice(2)`,
+/// moving bytes from one socket to the other through a pipe without
+/// copying them into userspace.
+///
+/// Like [`copy_bidirectional`], the write half of a stream is shut down
+/// once the other stream reaches EOF, and the amount of bytes sent in each
+/// direction is returned once both directions are done. If the pipes can't
+/// be created (or on platforms without `splice`), data is copied instead.
+pub async fn splice_bidirectional(a: &mut TcpStream, b: &mut TcpStream) -> io::Result<(u64, u64)> {
+    let activity = Activity::new();
+    match splice_directions(a, b, &activity) {
+        Ok((a_to_b, b_to_a)) => return tokio::try_join!(a_to_b, b_to_a),
+        Err(error) => debug!("failed to splice, copying instead: {error}"),
+    }
+
+    copy_bidirectional(a, b).await
+}
+
+/// Futures splicing data from `a` to `b`, and from `b` to `a`, each shutting
+/// down the write half of its destination once its source reaches EOF.
+///
+/// Data moved in either direction is recorded on the session's activity.
+#[cfg(target_os = "linux")]
+pub(crate) fn splice_directions<'a>(
+    a: &'a TcpStream,
+    b: &'a TcpStream,
+    activity: &'a Activity,
+) -> io::Result<(
+    impl Future<Output = io::Result<u64>> + 'a,
+    impl Future<Output = io::Result<u64>> + 'a,
+)> {
+    let (a_to_b, b_to_a) = (linux::Pipe::new()?, linux::Pipe::new()?);
+    Ok((
+        linux::splice_one_way(a, b, a_to_b, activity),
+        linux::splice_one_way(b, a, b_to_a, activity),
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn splice_directions(
+    _: &TcpStream,
+    _: &TcpStream,
+    _: &Activity,
+) -> io::Result<(Ready<io::Result<u64>>, Ready<io::Result<u64>>)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "splice is only supported on linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::Activity;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::fd::FromRawFd;
+    use std::os::fd::OwnedFd;
+    use std::os::fd::RawFd;
+    use std::ptr;
+    use tokio::io::Interest;
+    use tokio::net::TcpStream;
+
+    /// Maximum bytes moved by a single `splice` call, matching the default
+    /// pipe capacity on Linux.
+    const MAX_SPLICE_LEN: usize = 64 * 1024;
+
+    /// Kernel buffer data is spliced through.
+    pub(super) struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        pub(super) fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+
+            // SAFETY: `fds` has room for the two descriptors written by pipe2.
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // SAFETY: pipe2 succeeded, so both descriptors are open and owned
+            // by nothing else.
+            Ok(unsafe {
+                Self {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                }
+            })
+        }
+    }
+
+    /// Move data from `src` to `dst` through the pipe until `src` reaches
+    /// EOF, then shut down the write half of `dst`.
+    ///
+    /// Both streams must be non-blocking. The pipe is drained before reading
+    /// more from `src`, so `EAGAIN` from a splice always refers to the
+    /// socket side of it.
+    pub(super) async fn splice_one_way(
+        src: &TcpStream,
+        dst: &TcpStream,
+        pipe: Pipe,
+        activity: &Activity,
+    ) -> io::Result<u64> {
+        let mut transferred = 0;
+
+        loop {
+            let len = wait_for(src, Interest::READABLE, || {
+                splice(src.as_raw_fd(), pipe.write.as_raw_fd(), MAX_SPLICE_LEN)
+            })
+            .await?;
+
+            if len == 0 {
+                shutdown_write(dst)?;
+                return Ok(transferred);
+            }
+
+            activity.touch();
+            let mut pending = len;
+            while pending > 0 {
+                pending -= wait_for(dst, Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), dst.as_raw_fd(), pending)
+                })
+                .await?;
+            }
+
+            transferred += len as u64;
+        }
+    }
+
+    /// Run a non-blocking operation once the stream is ready for it.
+    async fn wait_for<F>(stream: &TcpStream, interest: Interest, mut op: F) -> io::Result<usize>
+    where
+        F: FnMut() -> io::Result<usize>,
+    {
+        loop {
+            stream.ready(interest).await?;
+            match stream.try_io(interest, &mut op) {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        // SAFETY: both descriptors are open for the duration of the call, and
+        // null offsets are valid for pipes and sockets.
+        let result = unsafe {
+            libc::splice(
+                from,
+                ptr::null_mut(),
+                to,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(result as usize)
+    }
+
+    fn shutdown_write(stream: &TcpStream) -> io::Result<()> {
+        // SAFETY: the descriptor is owned by `stream` and open for the call.
+        if unsafe { libc::shutdown(stream.as_raw_fd(), libc::SHUT_WR) } == -1 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::NotConnected {
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::splice_bidirectional;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+
+    /// Connected pair of TCP streams.
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, accepted) = tokio::join!(client, listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_splice_bidirectional_works() {
+        let (mut client, mut proxy_in) = pair().await;
+        let (mut proxy_out, mut target) = pair().await;
+        let forward =
+            tokio::spawn(async move { splice_bidirectional(&mut proxy_in, &mut proxy_out).await });
+
+        let payload = (0..1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let upload = payload.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&upload).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+            reply
+        });
+
+        let mut received = Vec::new();
+        target.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+
+        target.write_all(b"done").await.unwrap();
+        target.shutdown().await.unwrap();
+        assert_eq!(writer.await.unwrap(), b"done");
+        assert_eq!(forward.await.unwrap().unwrap(), (payload.len() as u64, 4));
+    }
+}
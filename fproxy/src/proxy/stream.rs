@@ -0,0 +1,158 @@
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::net::TcpStream;
+use tokio::net::UnixStream;
+use tokio_rustls::client::TlsStream;
+
+/// Connection accepted from a client.
+pub enum ClientStream {
+    /// Connection accepted on a TCP listener.
+    Tcp(TcpStream),
+
+    /// Connection accepted on a Unix domain socket listener.
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    /// IP address of the client, if connected over TCP.
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok().map(|addr| addr.ip()),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+/// Connection established with a target.
+pub enum TargetStream {
+    /// Plain TCP connection.
+    Tcp(TcpStream),
+
+    /// Connection to a Unix domain socket.
+    Unix(UnixStream),
+
+    /// TLS connection originated by the proxy.
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl TargetStream {
+    /// Description of the target the stream is connected to, for logging.
+    pub fn peer(&self) -> String {
+        let address = match self {
+            Self::Tcp(stream) => stream.peer_addr(),
+            Self::Tls(stream) => stream.get_ref().0.peer_addr(),
+            Self::Unix(stream) => {
+                return stream
+                    .peer_addr()
+                    .ok()
+                    .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+                    .unwrap_or_default()
+            }
+        };
+
+        address.map(|addr| addr.to_string()).unwrap_or_default()
+    }
+}
+
+/// Access to the TCP stream underneath a stream, if any.
+pub(crate) trait AsTcpStream {
+    /// TCP stream carrying the data as is, i.e. without TLS on top.
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream>;
+
+    /// TCP socket the stream is carried over, with or without TLS.
+    fn tcp_socket(&self) -> Option<&TcpStream>;
+}
+
+impl AsTcpStream for ClientStream {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            Self::Unix(_) => None,
+        }
+    }
+
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl AsTcpStream for TargetStream {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            Self::Unix(_) | Self::Tls(_) => None,
+        }
+    }
+
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            Self::Tls(stream) => Some(stream.get_ref().0),
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl AsTcpStream for tokio_rustls::server::TlsStream<ClientStream> {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        None
+    }
+
+    fn tcp_socket(&self) -> Option<&TcpStream> {
+        self.get_ref().0.tcp_socket()
+    }
+}
+
+/// Implement [`AsyncRead`] and [`AsyncWrite`] for an enum of streams by
+/// delegating to whichever stream the variant holds.
+macro_rules! delegate_async_io {
+    ($ty:ident { $($variant:ident),+ }) => {
+        impl AsyncRead for $ty {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<io::Result<()>> {
+                match self.get_mut() {
+                    $(Self::$variant(stream) => Pin::new(stream).poll_read(cx, buf),)+
+                }
+            }
+        }
+
+        impl AsyncWrite for $ty {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                match self.get_mut() {
+                    $(Self::$variant(stream) => Pin::new(stream).poll_write(cx, buf),)+
+                }
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                match self.get_mut() {
+                    $(Self::$variant(stream) => Pin::new(stream).poll_flush(cx),)+
+                }
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                match self.get_mut() {
+                    $(Self::$variant(stream) => Pin::new(stream).poll_shutdown(cx),)+
+                }
+            }
+        }
+    };
+}
+
+delegate_async_io!(ClientStream { Tcp, Unix });
+delegate_async_io!(TargetStream { Tcp, Unix, Tls });
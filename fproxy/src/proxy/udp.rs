@@ -0,0 +1,422 @@
+use crate::config::SessionLimitPolicy;
+use crate::proxy::admit_client;
+use crate::proxy::client::TargetClient;
+use crate::proxy::error::Error;
+use crate::proxy::session::Activity;
+use crate::proxy::Listener;
+use crate::proxy::ProxyConfig;
+use crate::proxy::Route;
+use crate::proxy::Router;
+use crate::proxy::ShutdownReport;
+use crate::proxy::Signal;
+use log::as_serde;
+use log::debug;
+use log::error;
+use lru_cache::LruCache;
+use std::io;
+use std::io::ErrorKind;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::spawn;
+use tokio::sync::broadcast::Receiver;
+use tokio::task::JoinHandle;
+use tokio::task::JoinSet;
+use tokio::time::interval;
+
+/// Upstream socket dedicated to a single client address.
+///
+/// Datagrams from the client are sent through the upstream socket, and
+/// replies received on it are relayed back to the client from the
+/// listening socket.
+struct Session {
+    upstream: Arc<UdpSocket>,
+    activity: Arc<Activity>,
+    relay: JoinHandle<()>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.relay.abort();
+    }
+}
+
+/// Session of a client, in the order its client last sent a datagram.
+enum Slot {
+    /// Upstream socket being connected, with the datagrams received
+    /// meanwhile.
+    Opening(Vec<Vec<u8>>),
+    Open(Session),
+}
+
+/// Most datagrams kept for a client while its session opens. Later ones are
+/// dropped.
+const MAX_PENDING_DATAGRAMS: usize = 16;
+
+/// Relay datagrams between clients and targets.
+///
+/// Sessions are keyed by client address, and expire once no datagram has
+/// been seen in either direction for the app's idle timeout, or the proxy's
+/// default if the app doesn't set one. Datagrams carry no session state the
+/// proxy could wait for, so sessions are closed right away on shutdown.
+///
+/// New clients are checked against the app's access list and rate limit,
+/// and count against the listener's session limit, before their session is
+/// opened. Sessions are opened in the background, so resolving and
+/// connecting a target doesn't hold up datagrams of other clients.
+pub(crate) async fn handle_datagrams(
+    config: Arc<ProxyConfig>,
+    listener: Arc<Listener>,
+    mut signal_rx: Receiver<Signal>,
+) -> ShutdownReport {
+    let (Listener::Udp(listener), Router::App(route)) = (&*listener, &config.router) else {
+        error!("udp proxy requires a udp listener and a single app route");
+        return ShutdownReport::default();
+    };
+
+    let mut sessions = LruCache::<SocketAddr, Slot>::new(config.max_udp_sessions);
+    let mut opening = JoinSet::new();
+    let mut buf = vec![0; config.max_datagram_size];
+    let idle_timeout = route
+        .idle_timeout
+        .unwrap_or(config.udp_session_idle_timeout);
+    let mut sweep = interval(idle_timeout / 2);
+
+    loop {
+        tokio::select! {
+            Ok(Signal::SIGTERM) = signal_rx.recv() => break,
+            _ = sweep.tick() => expire_sessions(&mut sessions, idle_timeout),
+            Some(Ok((client, result))) = opening.join_next() => {
+                // The client's slot may have been evicted, or reopened,
+                // meanwhile.
+                let Some(slot) = sessions.get_mut(&client) else {
+                    continue;
+                };
+                let Slot::Opening(pending) = slot else {
+                    continue;
+                };
+
+                match result {
+                    Ok(session) => {
+                        for datagram in mem::take(pending) {
+                            relay(&session, client, &datagram);
+                        }
+                        *slot = Slot::Open(session);
+                    }
+                    Err(error) => {
+                        debug!(client = as_serde!(client.to_string()); "failed to open udp session: {}", error);
+                        sessions.remove(&client);
+                    }
+                }
+            }
+            Ok((len, client)) = listener.recv_from(&mut buf) => {
+                let datagram = &buf[..len];
+                // A session whose target socket failed is opened again.
+                if matches!(sessions.get_mut(&client), Some(Slot::Open(session)) if session.relay.is_finished()) {
+                    sessions.remove(&client);
+                }
+
+                match sessions.get_mut(&client) {
+                    Some(Slot::Open(session)) => relay(session, client, datagram),
+                    Some(Slot::Opening(pending)) => {
+                        if pending.len() < MAX_PENDING_DATAGRAMS {
+                            pending.push(datagram.to_vec());
+                        }
+                    }
+                    None => {
+                        if let Err(error) = admit_session(&config, route, &mut sessions, client) {
+                            debug!(client = as_serde!(client.to_string()); "dropped datagram: {}", error);
+                            continue;
+                        }
+
+                        sessions.insert(client, Slot::Opening(vec![datagram.to_vec()]));
+                        opening.spawn({
+                            let (config, route, listener) = (config.clone(), route.clone(), listener.clone());
+                            async move { (client, open_session(config, route, listener, client).await) }
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    ShutdownReport {
+        closed: sessions
+            .iter()
+            .filter(|(_, slot)| matches!(slot, Slot::Open(_)))
+            .count(),
+        ..Default::default()
+    }
+}
+
+/// Check a new client against the app's access list and rate limit, and
+/// make room for its session if the listener's session limit is reached.
+fn admit_session(
+    config: &ProxyConfig,
+    route: &Route,
+    sessions: &mut LruCache<SocketAddr, Slot>,
+    client: SocketAddr,
+) -> Result<(), Error> {
+    let full = sessions.len() >= sessions.capacity();
+    if full && config.udp_session_limit_policy == SessionLimitPolicy::Reject {
+        return Err(Error::SessionLimit);
+    }
+
+    admit_client(route, client.ip())?;
+    if full {
+        sessions.remove_lru();
+    }
+
+    Ok(())
+}
+
+/// Close sessions that have been idle for `idle_timeout`, or whose target
+/// socket failed.
+fn expire_sessions(sessions: &mut LruCache<SocketAddr, Slot>, idle_timeout: Duration) {
+    let idle = sessions
+        .iter()
+        .filter(|(_, slot)| {
+            matches!(slot, Slot::Open(session)
+                if session.activity.idle() >= idle_timeout || session.relay.is_finished())
+        })
+        .map(|(client, _)| *client)
+        .collect::<Vec<_>>();
+
+    for client in idle {
+        sessions.remove(&client);
+    }
+}
+
+/// Send a client's datagram to the target of its session.
+///
+/// The datagram is dropped if the target socket's send buffer is full, as
+/// the network would, rather than holding up datagrams of other clients.
+fn relay(session: &Session, client: SocketAddr, datagram: &[u8]) {
+    session.activity.touch();
+    let mut result = session.upstream.try_send(datagram);
+    // Sending fails without sending anything when an error was reported for
+    // an earlier datagram.
+    if matches!(&result, Err(error) if error.kind() != ErrorKind::WouldBlock && is_transient(error))
+    {
+        result = session.upstream.try_send(datagram);
+    }
+
+    match result {
+        Ok(_) => {}
+        Err(error) if error.kind() == ErrorKind::WouldBlock => {
+            debug!(client = as_serde!(client.to_string()); "target socket is full, dropped datagram");
+        }
+        Err(error) => {
+            debug!(client = as_serde!(client.to_string()); "write to target failed: {}", error);
+        }
+    }
+}
+
+/// Returns `true` if an error on a target socket only concerns an earlier
+/// datagram, e.g. an ICMP error the target's network reported for
+/// it, so the socket can still be read.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(
+            libc::ECONNREFUSED
+                | libc::EHOSTUNREACH
+                | libc::ENETUNREACH
+                | libc::EHOSTDOWN
+                | libc::ENETDOWN
+                | libc::EINTR
+                | libc::EAGAIN
+        )
+    )
+}
+
+/// Connect a new upstream socket for a client, and start relaying the
+/// target's replies back to it.
+async fn open_session(
+    config: Arc<ProxyConfig>,
+    route: Arc<Route>,
+    listener: Arc<UdpSocket>,
+    client: SocketAddr,
+) -> Result<Session, Error> {
+    let upstream = Arc::new(
+        TargetClient::new(config.clone(), route)
+            .connect_udp()
+            .await?,
+    );
+    // Datagrams are sent without waiting, which tokio only allows once the
+    // socket reported being writable.
+    upstream.writable().await?;
+    let activity = Arc::new(Activity::new());
+
+    let relay = spawn({
+        let upstream = upstream.clone();
+        let activity = activity.clone();
+        let mut buf = vec![0; config.max_datagram_size];
+        async move {
+            loop {
+                let len = match upstream.recv(&mut buf).await {
+                    Ok(len) => len,
+                    Err(error) if is_transient(&error) => {
+                        debug!(client = as_serde!(client.to_string()); "read from target failed: {}", error);
+                        continue;
+                    }
+                    Err(error) => {
+                        debug!(client = as_serde!(client.to_string()); "read from target failed, closing session: {}", error);
+                        return;
+                    }
+                };
+
+                activity.touch();
+                if let Err(error) = listener.send_to(&buf[..len], client).await {
+                    debug!(client = as_serde!(client.to_string()); "write to client failed: {}", error);
+                }
+            }
+        }
+    });
+
+    Ok(Session {
+        upstream,
+        activity,
+        relay,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::SessionLimitPolicy;
+    use crate::config::TargetAddr;
+    use crate::dns::default_async_dns_resolver;
+    use crate::proxy::Listener;
+    use crate::proxy::Proxy;
+    use crate::proxy::ProxyConfig;
+    use crate::proxy::Route;
+    use crate::proxy::Router;
+    use crate::strategy::RoundRobinStrategy;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+
+    /// Start a target echoing every datagram back.
+    async fn echo_target() -> SocketAddr {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 64];
+            loop {
+                let (len, peer) = target.recv_from(&mut buf).await.unwrap();
+                target.send_to(&buf[..len], peer).await.unwrap();
+            }
+        });
+
+        target_addr
+    }
+
+    async fn udp_proxy(
+        target_addr: SocketAddr,
+        max_sessions: usize,
+        policy: SessionLimitPolicy,
+    ) -> (Proxy, SocketAddr) {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let route = Route::builder()
+            .app("udp-echo".to_owned())
+            .target_resolver(Arc::new(RoundRobinStrategy::new(vec![TargetAddr::Inet {
+                addr: "127.0.0.1".to_owned(),
+                port: target_addr.port(),
+            }])))
+            .build();
+        let proxy = Proxy::listen(
+            ProxyConfig::builder()
+                .listeners(vec![Listener::Udp(Arc::new(listener))])
+                .dns_resolver(default_async_dns_resolver().await.unwrap())
+                .router(Router::App(Arc::new(route)))
+                .max_udp_sessions(max_sessions)
+                .udp_session_limit_policy(policy)
+                .build(),
+        );
+
+        (proxy, proxy_addr)
+    }
+
+    async fn client(proxy_addr: SocketAddr) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(proxy_addr).await.unwrap();
+        socket
+    }
+
+    /// Send a datagram, and wait briefly for it to be echoed.
+    async fn echoed(socket: &UdpSocket, payload: &[u8]) -> bool {
+        socket.send(payload).await.unwrap();
+        let mut buf = [0; 64];
+        match timeout(Duration::from_millis(500), socket.recv(&mut buf)).await {
+            Ok(len) => &buf[..len.unwrap()] == payload,
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_udp_sessions_relay_replies() {
+        let target_addr = echo_target().await;
+        let (_proxy, proxy_addr) = udp_proxy(target_addr, 65536, SessionLimitPolicy::Reject).await;
+
+        for name in ["first", "second"] {
+            let socket = client(proxy_addr).await;
+            for _ in 0..2 {
+                socket.send(name.as_bytes()).await.unwrap();
+                let mut buf = [0; 64];
+                let len = timeout(Duration::from_secs(5), socket.recv(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&buf[..len], name.as_bytes());
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_refused_datagrams_keep_sessions_open() {
+        let target_addr = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (_proxy, proxy_addr) = udp_proxy(target_addr, 65536, SessionLimitPolicy::Reject).await;
+
+        // The target isn't up yet, so the datagram is refused.
+        let socket = client(proxy_addr).await;
+        assert!(!echoed(&socket, b"refused").await);
+
+        let target = UdpSocket::bind(target_addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 64];
+            loop {
+                let (len, peer) = target.recv_from(&mut buf).await.unwrap();
+                target.send_to(&buf[..len], peer).await.unwrap();
+            }
+        });
+        assert!(echoed(&socket, b"relayed").await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_udp_session_limit() {
+        let target_addr = echo_target().await;
+
+        // New clients are dropped until a session expires.
+        let (_proxy, proxy_addr) = udp_proxy(target_addr, 1, SessionLimitPolicy::Reject).await;
+        let (first, second) = (client(proxy_addr).await, client(proxy_addr).await);
+        assert!(echoed(&first, b"first").await);
+        assert!(!echoed(&second, b"second").await);
+        assert!(echoed(&first, b"first").await);
+
+        // The least recently active client makes room for the new one.
+        let (_proxy, proxy_addr) = udp_proxy(target_addr, 1, SessionLimitPolicy::Evict).await;
+        let (first, second) = (client(proxy_addr).await, client(proxy_addr).await);
+        assert!(echoed(&first, b"first").await);
+        assert!(echoed(&second, b"second").await);
+        assert!(echoed(&first, b"first").await);
+    }
+}
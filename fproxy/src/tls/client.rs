@@ -0,0 +1,206 @@
+use crate::config::TlsVerify;
+use crate::config::UpstreamTlsConfig;
+use crate::tls::load_certs;
+use crate::tls::load_private_key;
+use crate::tls::TlsError;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::client::WebPkiVerifier;
+use rustls::Certificate;
+use rustls::ClientConfig;
+use rustls::OwnedTrustAnchor;
+use rustls::RootCertStore;
+use rustls::ServerName;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// Originates TLS (optionally with a client certificate) to an app's targets.
+pub struct UpstreamTls {
+    connector: TlsConnector,
+
+    /// Server name overriding the target's address.
+    server_name: Option<ServerName>,
+}
+
+impl UpstreamTls {
+    /// Build the client side TLS configuration for an app.
+    pub fn new(config: &UpstreamTlsConfig) -> Result<Self, TlsError> {
+        let verifier: Arc<dyn ServerCertVerifier> = match config.verify {
+            TlsVerify::Full => Arc::new(WebPkiVerifier::new(root_store(config)?, None)),
+            TlsVerify::None => Arc::new(NoVerification),
+        };
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(verifier);
+
+        let client_config = match (&config.cert_path, &config.key_path) {
+            (None, None) => builder.with_no_client_auth(),
+            (Some(cert_path), Some(key_path)) => builder
+                .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)?,
+            (Some(_), None) | (None, Some(_)) => return Err(TlsError::IncompleteClientAuth),
+        };
+
+        let server_name = config
+            .server_name
+            .as_deref()
+            .map(|name| {
+                ServerName::try_from(name).map_err(|_| TlsError::InvalidServerName(name.to_owned()))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name,
+        })
+    }
+
+    /// Perform the TLS handshake with a target over an established stream.
+    ///
+    /// The server name defaults to the target's address when not overridden.
+    pub async fn connect(
+        &self,
+        target_addr: &str,
+        stream: TcpStream,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let server_name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => ServerName::try_from(target_addr)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?,
+        };
+
+        self.connector.connect(server_name, stream).await
+    }
+}
+
+fn root_store(config: &UpstreamTlsConfig) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    let Some(path) = &config.ca_bundle else {
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        return Ok(roots);
+    };
+
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+
+    Ok(roots)
+}
+
+/// Accepts any certificate presented by a target.
+pub(crate) struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _: &Certificate,
+        _: &[Certificate],
+        _: &ServerName,
+        _: &mut dyn Iterator<Item = &[u8]>,
+        _: &[u8],
+        _: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::UpstreamTls;
+    use crate::config::TlsVerify;
+    use crate::config::UpstreamTlsConfig;
+    use crate::tls::load_certs;
+    use crate::tls::load_private_key;
+    use rcgen::BasicConstraints;
+    use rcgen::Certificate;
+    use rcgen::CertificateParams;
+    use rcgen::IsCa;
+    use rustls::server::AllowAnyAuthenticatedClient;
+    use rustls::RootCertStore;
+    use rustls::ServerConfig;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Write a CA, and a server and client certificate signed by it, into `dir`.
+    fn write_certificates(dir: &Path) {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(params).unwrap();
+        fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+        for (name, san) in [("server", "target.internal"), ("client", "proxy.internal")] {
+            let cert =
+                Certificate::from_params(CertificateParams::new(vec![san.to_owned()])).unwrap();
+            let pem = cert.serialize_pem_with_signer(&ca).unwrap();
+            fs::write(dir.join(format!("{name}.pem")), pem).unwrap();
+            let key = cert.serialize_private_key_pem();
+            fs::write(dir.join(format!("{name}-key.pem")), key).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_mutual_tls_works() {
+        let dir = tempfile::tempdir().unwrap();
+        write_certificates(dir.path());
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&load_certs(&dir.path().join("ca.pem")).unwrap()[0])
+            .unwrap();
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            .with_single_cert(
+                load_certs(&dir.path().join("server.pem")).unwrap(),
+                load_private_key(&dir.path().join("server-key.pem")).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            buf
+        });
+
+        let upstream = UpstreamTls::new(&UpstreamTlsConfig {
+            server_name: Some("target.internal".to_owned()),
+            ca_bundle: Some(dir.path().join("ca.pem")),
+            cert_path: Some(dir.path().join("client.pem")),
+            key_path: Some(dir.path().join("client-key.pem")),
+            verify: TlsVerify::Full,
+        })
+        .unwrap();
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut stream = upstream.connect("127.0.0.1", stream).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(&buf, b"pong");
+        assert_eq!(&server.await.unwrap(), b"ping");
+    }
+}
@@ -0,0 +1,42 @@
+use notify::Error as NotifyError;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("failed to read {0:?}: {1}")]
+    Io(PathBuf, std::io::Error),
+
+    #[error("no certificate found in {0:?}")]
+    MissingCertificate(PathBuf),
+
+    #[error("no supported private key found in {0:?}")]
+    MissingKey(PathBuf),
+
+    #[error("private key in {0:?} doesn't match the certificate")]
+    KeyMismatch(PathBuf),
+
+    #[error("client certificate and key must be set together")]
+    IncompleteClientAuth,
+
+    #[error("invalid server name {0:?}")]
+    InvalidServerName(String),
+
+    #[error("invalid tls configuration: {0}")]
+    Rustls(rustls::Error),
+
+    #[error("failed to watch certificate files: {0}")]
+    Watcher(NotifyError),
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        Self::Rustls(e)
+    }
+}
+
+impl From<NotifyError> for TlsError {
+    fn from(e: NotifyError) -> Self {
+        Self::Watcher(e)
+    }
+}
@@ -0,0 +1,7 @@
+mod client;
+mod error;
+mod server;
+
+pub use self::client::*;
+pub use self::error::*;
+pub use self::server::*;
@@ -0,0 +1,383 @@
+use crate::config::TlsConfig;
+use crate::config::TlsVersion;
+use crate::tls::TlsError;
+use log::debug;
+use log::error;
+use log::info;
+use notify::Event;
+use notify::EventKind;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use rustls::server::ClientHello;
+use rustls::server::ResolvesServerCert;
+use rustls::sign::any_supported_type;
+use rustls::sign::CertifiedKey;
+use rustls::sign::SigningKey;
+use rustls::Certificate;
+use rustls::PrivateKey;
+use rustls::ServerConfig;
+use rustls::SignatureScheme;
+use rustls::SupportedProtocolVersion;
+use rustls_pemfile::Item;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+use tokio_rustls::TlsAcceptor;
+use webpki::EndEntityCert;
+use webpki::SignatureAlgorithm;
+
+/// Protocol versions accepted when TLS 1.3 is the minimum.
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// Schemes a private key is checked against its certificate with, and the
+/// algorithm verifying each.
+static KEY_CHECK_SCHEMES: &[(SignatureScheme, &SignatureAlgorithm)] = &[
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Message signed to check a private key against its certificate.
+static KEY_CHECK_MESSAGE: &[u8] = b"fproxy certificate key check";
+
+/// Terminates TLS for an app, reloading its certificate whenever the
+/// certificate or key file changes on disk.
+///
+/// A reloaded certificate is only served once its key matches it, so a
+/// rotation writing the certificate and key one after the other keeps
+/// serving the previous pair until both are in place.
+pub struct TlsTerminator {
+    acceptor: TlsAcceptor,
+
+    /// Keeps the certificate watcher alive for as long as the terminator.
+    _watcher: RecommendedWatcher,
+}
+
+impl TlsTerminator {
+    /// Load the app's certificate and start watching it for changes.
+    pub fn new(config: &TlsConfig) -> Result<Self, TlsError> {
+        let resolver = Arc::new(CertificateResolver {
+            key: RwLock::new(load_certified_key(&config.cert_path, &config.key_path)?),
+            cert_path: config.cert_path.clone(),
+            key_path: config.key_path.clone(),
+        });
+
+        let mut server_config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(protocol_versions(config.min_version))?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        server_config.alpn_protocols = config
+            .alpn
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            _watcher: watch_certificate(resolver)?,
+        })
+    }
+
+    /// Acceptor performing the server side of the TLS handshake.
+    pub fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+}
+
+/// Hands out the most recently loaded certificate for every handshake.
+struct CertificateResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl CertificateResolver {
+    /// Reload the certificate from disk. On failure, e.g. if the key
+    /// doesn't match the certificate yet, the previously loaded
+    /// certificate keeps being served.
+    fn reload(&self) {
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Err(err) => error!("failed to reload certificate: {}", err),
+            Ok(key) => {
+                info!("reloaded certificate {:?}", self.cert_path);
+                *self.key.write().unwrap() = key;
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+/// Watch the directories of the certificate and key, rather than the files
+/// themselves, so that rotations replacing the files (e.g. renames) are
+/// picked up as well.
+fn watch_certificate(resolver: Arc<CertificateResolver>) -> Result<RecommendedWatcher, TlsError> {
+    let paths = [
+        watched_path(&resolver.cert_path),
+        watched_path(&resolver.key_path),
+    ];
+
+    let event_handler = {
+        let paths = paths.clone();
+        move |result: Result<Event, notify::Error>| match result {
+            Err(err) => error!("{}", err),
+            Ok(event) => {
+                if matches!(event.kind, EventKind::Access(_))
+                    || !event
+                        .paths
+                        .iter()
+                        .any(|path| paths.iter().any(|(_, p)| p == path))
+                {
+                    return;
+                }
+
+                debug!("received certificate event for: ({:?})", event.paths);
+                resolver.reload();
+            }
+        }
+    };
+
+    let mut watcher = RecommendedWatcher::new(event_handler, notify::Config::default())?;
+    for (dir, _) in &paths {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    Ok(watcher)
+}
+
+/// Absolute directory to watch for a file, and the absolute file path
+/// events for it will be reported with.
+fn watched_path(path: &Path) -> (PathBuf, PathBuf) {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned());
+    let file = dir.join(path.file_name().unwrap_or_default());
+
+    (dir, file)
+}
+
+fn protocol_versions(min_version: TlsVersion) -> &'static [&'static SupportedProtocolVersion] {
+    match min_version {
+        TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+        TlsVersion::Tls13 => TLS13_ONLY,
+    }
+}
+
+/// Load a PEM certificate chain and private key into a signing key,
+/// checking the key belongs to the leaf certificate.
+pub(crate) fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<Arc<CertifiedKey>, TlsError> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let key = any_supported_type(&key).map_err(|_| TlsError::MissingKey(key_path.to_owned()))?;
+
+    if !key_matches(&certs[0], key.as_ref()) {
+        return Err(TlsError::KeyMismatch(key_path.to_owned()));
+    }
+
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+/// Whether `key` belongs to `cert`, i.e. the certificate's public key
+/// verifies a signature made with it.
+fn key_matches(cert: &Certificate, key: &dyn SigningKey) -> bool {
+    let Ok(cert) = EndEntityCert::try_from(cert.0.as_slice()) else {
+        return false;
+    };
+    let schemes: Vec<_> = KEY_CHECK_SCHEMES
+        .iter()
+        .map(|(scheme, _)| *scheme)
+        .collect();
+    let Some(signer) = key.choose_scheme(&schemes) else {
+        return false;
+    };
+    let Ok(signature) = signer.sign(KEY_CHECK_MESSAGE) else {
+        return false;
+    };
+
+    KEY_CHECK_SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .is_some_and(|(_, algorithm)| {
+            cert.verify_signature(algorithm, KEY_CHECK_MESSAGE, &signature)
+                .is_ok()
+        })
+}
+
+/// Load every certificate in a PEM file.
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let file = File::open(path).map_err(|e| TlsError::Io(path.to_owned(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| TlsError::Io(path.to_owned(), e))?;
+
+    if certs.is_empty() {
+        return Err(TlsError::MissingCertificate(path.to_owned()));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Load the first private key in a PEM file.
+pub(crate) fn load_private_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let file = File::open(path).map_err(|e| TlsError::Io(path.to_owned(), e))?;
+    let mut reader = BufReader::new(file);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|e| TlsError::Io(path.to_owned(), e))? {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => continue,
+            None => return Err(TlsError::MissingKey(path.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::load_certified_key;
+    use super::load_certs;
+    use super::TlsTerminator;
+    use crate::config::TlsConfig;
+    use crate::config::TlsVersion;
+    use crate::tls::NoVerification;
+    use crate::tls::TlsError;
+    use rustls::ClientConfig;
+    use rustls::ServerName;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+
+    /// Write a fresh self-signed certificate for `localhost` into `dir`,
+    /// returning its DER encoding.
+    fn write_certificate(dir: &Path) -> Vec<u8> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        fs::write(dir.join("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        load_certs(&dir.join("cert.pem")).unwrap().remove(0).0
+    }
+
+    /// Perform a handshake against the terminator and return the
+    /// certificate and ALPN protocol the server presented.
+    async fn handshake(terminator: &TlsTerminator) -> (Vec<u8>, Option<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoVerification))
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut stream = connector.connect(name, stream).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+
+            let (_, connection) = stream.get_ref();
+            let cert = connection.peer_certificates().unwrap()[0].0.clone();
+            (cert, connection.alpn_protocol().map(<[u8]>::to_vec))
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = terminator.acceptor().accept(stream).await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        client.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_terminator_reloads_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_certificate(dir.path());
+        let terminator = TlsTerminator::new(&TlsConfig {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+            alpn: vec!["h2".to_owned()],
+            min_version: TlsVersion::Tls12,
+        })
+        .unwrap();
+
+        let (cert, alpn) = handshake(&terminator).await;
+        assert_eq!(cert, first);
+        assert_eq!(alpn.as_deref(), Some(&b"h2"[..]));
+
+        let second = write_certificate(dir.path());
+        for _ in 0..50 {
+            if handshake(&terminator).await.0 == second {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        panic!("certificate was not reloaded");
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_key_is_not_served() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_certificate(dir.path());
+        let terminator = TlsTerminator::new(&TlsConfig {
+            cert_path: dir.path().join("cert.pem"),
+            key_path: dir.path().join("key.pem"),
+            alpn: vec![],
+            min_version: TlsVersion::Tls12,
+        })
+        .unwrap();
+
+        // Rotate the key without the certificate.
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        fs::write(
+            dir.path().join("key.pem"),
+            other.serialize_private_key_pem(),
+        )
+        .unwrap();
+        assert!(matches!(
+            load_certified_key(&dir.path().join("cert.pem"), &dir.path().join("key.pem")),
+            Err(TlsError::KeyMismatch(_))
+        ));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(handshake(&terminator).await.0, first);
+    }
+}